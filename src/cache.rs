@@ -0,0 +1,136 @@
+use anyhow::bail;
+use std::cell::OnceCell;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use tempfile::TempDir;
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct CacheArgs {
+    /// Directory for compiled crates that are kept between runs.
+    /// Defaults to $XDG_CACHE_HOME/rustkrazy_packer or ~/.cache/rustkrazy_packer.
+    #[arg(long = "cache-dir")]
+    cache_dir: Option<PathBuf>,
+    /// Compile all crates from scratch in a temporary directory.
+    #[arg(long = "no-cache", conflicts_with = "cache_dir")]
+    no_cache: bool,
+}
+
+impl CacheArgs {
+    /// Returns the cache directory, or `None` if caching is disabled.
+    pub fn dir(&self) -> anyhow::Result<Option<PathBuf>> {
+        if self.no_cache {
+            return Ok(None);
+        }
+
+        if let Some(dir) = &self.cache_dir {
+            return Ok(Some(dir.clone()));
+        }
+
+        let base = match env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None => match env::var_os("HOME") {
+                Some(home) => PathBuf::from(home).join(".cache"),
+                None => bail!("can't locate the cache directory, use --cache-dir or --no-cache"),
+            },
+        };
+
+        Ok(Some(base.join("rustkrazy_packer")))
+    }
+}
+
+/// Where the crates of one image are compiled and installed.
+///
+/// With a cache directory everything lives in a subdirectory per target triple:
+/// a target directory shared by all crates, compiler wrapper scripts
+/// and an install root per crate and build key (source, rustc version and toolchain settings).
+/// Cargo's install record in each root takes care of the crate version and features
+/// and its fingerprints in the target directory of everything else,
+/// so only crates that changed are compiled again.
+#[derive(Debug)]
+pub struct Builds {
+    dir: PathBuf,
+    /// Toolchain settings shared by all crates of the image, `None` if caching is disabled.
+    toolchain: Option<String>,
+    /// Queried on first use so that images without crates don't need a compiler.
+    rustc_version: OnceCell<String>,
+    /// Keeps a temporary directory alive if caching is disabled.
+    _tmp: Option<TempDir>,
+}
+
+impl Builds {
+    pub fn open(cache: Option<&PathBuf>, triple: &str, toolchain: &str) -> anyhow::Result<Self> {
+        match cache {
+            Some(cache) => {
+                let dir = cache.join(triple);
+                fs::create_dir_all(&dir)?;
+
+                Ok(Self {
+                    dir,
+                    toolchain: Some(toolchain.to_owned()),
+                    rustc_version: OnceCell::new(),
+                    _tmp: None,
+                })
+            }
+            None => {
+                let tmp = tempfile::tempdir()?;
+
+                Ok(Self {
+                    dir: tmp.path().to_path_buf(),
+                    toolchain: None,
+                    rustc_version: OnceCell::new(),
+                    _tmp: Some(tmp),
+                })
+            }
+        }
+    }
+
+    pub fn target_dir(&self) -> PathBuf {
+        self.dir.join("target")
+    }
+
+    /// Returns the directory for compiler wrapper scripts.
+    pub fn toolchain_dir(&self) -> PathBuf {
+        self.dir.join("toolchain")
+    }
+
+    /// Returns the install root of a crate. `source` is the crate's registry or git URL.
+    pub fn root(&self, pkg: &str, source: &str) -> anyhow::Result<PathBuf> {
+        match &self.toolchain {
+            Some(toolchain) => {
+                let rustc_version = match self.rustc_version.get() {
+                    Some(version) => version,
+                    None => {
+                        let version = rustc_version()?;
+                        self.rustc_version.get_or_init(|| version)
+                    }
+                };
+
+                let key = format!("{}\n{}\n{}", source, rustc_version, toolchain);
+                let hash = crate::instance::sha256(key.as_bytes());
+
+                Ok(self
+                    .dir
+                    .join("crates")
+                    .join(format!("{}-{}", pkg, &hash[..16])))
+            }
+            None => Ok(self.dir.join(pkg)),
+        }
+    }
+}
+
+/// Returns the version information of the compiler cargo uses.
+fn rustc_version() -> anyhow::Result<String> {
+    let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+
+    let output = Command::new(&rustc)
+        .arg("-vV")
+        .stdin(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        bail!("{} -vV failed", rustc.to_string_lossy());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -0,0 +1,104 @@
+use anyhow::bail;
+
+/// The xattr holding file capabilities.
+pub const XATTR_NAME: &str = "security.capability";
+
+const VFS_CAP_REVISION_2: u32 = 0x02000000;
+const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x000001;
+
+// Indexed by capability number, see capability.h.
+const CAPABILITIES: &[&str] = &[
+    "chown",
+    "dac_override",
+    "dac_read_search",
+    "fowner",
+    "fsetid",
+    "kill",
+    "setgid",
+    "setuid",
+    "setpcap",
+    "linux_immutable",
+    "net_bind_service",
+    "net_broadcast",
+    "net_admin",
+    "net_raw",
+    "ipc_lock",
+    "ipc_owner",
+    "sys_module",
+    "sys_rawio",
+    "sys_chroot",
+    "sys_ptrace",
+    "sys_pacct",
+    "sys_admin",
+    "sys_boot",
+    "sys_nice",
+    "sys_resource",
+    "sys_time",
+    "sys_tty_config",
+    "mknod",
+    "lease",
+    "audit_write",
+    "audit_control",
+    "setfcap",
+    "mac_override",
+    "mac_admin",
+    "syslog",
+    "wake_alarm",
+    "block_suspend",
+    "audit_read",
+    "perfmon",
+    "bpf",
+    "checkpoint_restore",
+];
+
+/// Encodes capability names like `CAP_NET_BIND_SERVICE` as a `security.capability` value.
+/// The capabilities are permitted and effective so that programs
+/// that don't manage their capabilities still get them.
+pub fn encode<S: AsRef<str>>(names: &[S]) -> anyhow::Result<Vec<u8>> {
+    let mut permitted = 0u64;
+
+    for name in names {
+        let name = name.as_ref().to_ascii_lowercase();
+        let short = name.strip_prefix("cap_").unwrap_or(&name);
+
+        match CAPABILITIES.iter().position(|cap| *cap == short) {
+            Some(bit) => permitted |= 1 << bit,
+            None => bail!("unknown capability: {}", name),
+        }
+    }
+
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&(VFS_CAP_REVISION_2 | VFS_CAP_FLAGS_EFFECTIVE).to_le_bytes());
+    buf.extend_from_slice(&(permitted as u32).to_le_bytes()); // permitted (low)
+    buf.extend_from_slice(&0u32.to_le_bytes()); // inheritable (low)
+    buf.extend_from_slice(&((permitted >> 32) as u32).to_le_bytes()); // permitted (high)
+    buf.extend_from_slice(&0u32.to_le_bytes()); // inheritable (high)
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_v2() {
+        let value = encode(&["CAP_NET_BIND_SERVICE", "sys_time", "cap_bpf"]).unwrap();
+
+        assert_eq!(
+            value,
+            [
+                0x01, 0x00, 0x00, 0x02, // revision 2, effective
+                0x00, 0x04, 0x00, 0x02, // permitted: net_bind_service (10), sys_time (25)
+                0x00, 0x00, 0x00, 0x00, // inheritable
+                0x80, 0x00, 0x00, 0x00, // permitted: bpf (39)
+                0x00, 0x00, 0x00, 0x00, // inheritable
+            ]
+        );
+    }
+
+    #[test]
+    fn encode_unknown() {
+        assert!(encode(&["CAP_FLY"]).is_err());
+    }
+}
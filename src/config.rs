@@ -0,0 +1,317 @@
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+
+/// Image settings that don't fit on the command line,
+/// read from the JSON file passed to `--config`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Hostname written to /etc/hostname and /etc/hosts.
+    pub hostname: String,
+    /// Nameservers written to /etc/resolv.conf.
+    /// If empty /etc/resolv.conf links to /run/resolv.conf
+    /// so that it can be managed at runtime.
+    pub nameservers: Vec<String>,
+    /// Service users to add to /etc/passwd in addition to root.
+    pub users: Vec<User>,
+    /// Groups to add to /etc/group in addition to root.
+    pub groups: Vec<Group>,
+    /// Symlinks to create in the root filesystem, mapping path to target.
+    pub symlinks: BTreeMap<String, String>,
+    /// Hardlinks to create in the root filesystem,
+    /// mapping path to an existing non-directory path.
+    pub hardlinks: BTreeMap<String, String>,
+    /// Static device nodes to create in the root filesystem.
+    pub devices: Vec<Device>,
+    /// Host directory whose contents are copied into the root filesystem.
+    /// Modes are preserved, ownership is reset to root.
+    pub overlay: Option<String>,
+    /// Files to add to the root filesystem or whose metadata to change,
+    /// e.g. to install a secret or set capabilities on a binary.
+    pub files: BTreeMap<String, FileAttributes>,
+    /// Root filesystem compression. Uses the squashfs library defaults if unset.
+    pub compression: Option<Compression>,
+    /// Data partition settings.
+    pub data: Data,
+    /// Cross-compilation settings by architecture (x86_64, rpi).
+    pub toolchains: BTreeMap<String, Toolchain>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            hostname: String::from("rustkrazy"),
+            nameservers: Vec::new(),
+            users: Vec::new(),
+            groups: Vec::new(),
+            symlinks: BTreeMap::new(),
+            hardlinks: BTreeMap::new(),
+            devices: Vec::new(),
+            overlay: None,
+            files: BTreeMap::new(),
+            compression: None,
+            data: Data::default(),
+            toolchains: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct User {
+    pub name: String,
+    pub uid: u32,
+    /// Primary group. Defaults to a group with the name and id of the user.
+    pub gid: Option<u32>,
+    /// Supplementary groups by name.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default = "User::default_home")]
+    pub home: String,
+}
+
+impl User {
+    fn default_home() -> String {
+        String::from("/data")
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Group {
+    pub name: String,
+    pub gid: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Device {
+    pub path: String,
+    #[serde(rename = "type")]
+    pub kind: DeviceKind,
+    pub major: u32,
+    pub minor: u32,
+    #[serde(default = "Device::default_mode", with = "octal")]
+    pub mode: u16,
+}
+
+impl Device {
+    fn default_mode() -> u16 {
+        0o600
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+    Char,
+    Block,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileAttributes {
+    /// Host file to install at this path.
+    /// If unset the path must already exist in the image.
+    pub source: Option<String>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    #[serde(with = "octal::option")]
+    pub mode: Option<u16>,
+    /// Extended attributes. Values prefixed with `hex:` are hex encoded.
+    pub xattrs: BTreeMap<String, String>,
+    /// File capabilities like CAP_NET_BIND_SERVICE.
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Data {
+    pub filesystem: DataFilesystem,
+    pub label: Option<String>,
+    pub uuid: Option<String>,
+    /// Host directory whose contents are copied into the data partition.
+    /// Ownership and modes are preserved if the filesystem supports them.
+    pub seed: Option<String>,
+    /// Minimum size in bytes when the image size is determined automatically.
+    pub min_size: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataFilesystem {
+    #[default]
+    Ext4,
+    F2fs,
+    Btrfs,
+    Vfat,
+    /// Leave the space unpartitioned.
+    None,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Compression {
+    pub compressor: Compressor,
+    pub level: Option<u32>,
+    /// Block size in bytes.
+    pub block_size: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compressor {
+    Gzip,
+    Lz4,
+    Lzo,
+    Xz,
+    Zstd,
+}
+
+/// Compilers and linker used to build the crates.
+/// Settings given explicitly override those of the preset.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Toolchain {
+    pub preset: Option<Preset>,
+    /// Linker passed to rustc.
+    pub linker: Option<String>,
+    /// C compiler for the build scripts of sys crates.
+    pub cc: Option<String>,
+    /// C++ compiler for the build scripts of sys crates.
+    pub cxx: Option<String>,
+    pub ar: Option<String>,
+    /// Passed to the C and C++ compilers and the linker as --sysroot.
+    pub sysroot: Option<String>,
+    /// Extra arguments for rustc, e.g. ["-C", "target-cpu=cortex-a53"].
+    pub rustc_args: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+    /// <cpu>-linux-gnu-gcc and friends from the distribution's cross compiler packages.
+    Gnu,
+    /// clang --target, lld and llvm-ar.
+    Clang,
+    /// zig cc and zig ar.
+    Zig,
+    /// <cpu>-linux-musl-gcc and friends from musl-cross-make.
+    MuslCross,
+}
+
+impl Config {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+        let config: Self = serde_json::from_reader(file)?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects settings that contradict each other.
+    fn validate(&self) -> anyhow::Result<()> {
+        if let DataFilesystem::None = self.data.filesystem {
+            for (name, set) in [
+                ("seed", self.data.seed.is_some()),
+                ("label", self.data.label.is_some()),
+                ("uuid", self.data.uuid.is_some()),
+            ] {
+                if set {
+                    bail!("data.{} can't be used without a data filesystem", name);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// File modes are written as octal strings like "0644" since JSON lacks octal literals.
+mod octal {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(mode: &u16, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:04o}", mode))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+        parse(&String::deserialize(deserializer)?)
+    }
+
+    fn parse<E: Error>(s: &str) -> Result<u16, E> {
+        u16::from_str_radix(s, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .ok_or_else(|| E::custom(format!("invalid file mode: {}", s)))
+    }
+
+    pub mod option {
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            mode: &Option<u16>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match mode {
+                Some(mode) => super::serialize(mode, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<u16>, D::Error> {
+            // Instance files store unset modes as null.
+            Option::<String>::deserialize(deserializer)?
+                .map(|s| super::parse(&s))
+                .transpose()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(json: &str) -> anyhow::Result<Config> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.json");
+        std::fs::write(&path, json)?;
+
+        Config::load(path.to_str().unwrap())
+    }
+
+    #[test]
+    fn data_settings_need_a_filesystem() {
+        for field in [
+            r#""seed": "seed""#,
+            r#""label": "data""#,
+            r#""uuid": "1234-5678""#,
+        ] {
+            let json = format!(r#"{{ "data": {{ "filesystem": "none", {} }} }}"#, field);
+            assert!(load(&json).is_err(), "{}", json);
+        }
+
+        assert!(load(r#"{ "data": { "filesystem": "none" } }"#).is_ok());
+        assert!(load(r#"{ "data": { "filesystem": "vfat", "label": "data" } }"#).is_ok());
+    }
+
+    #[test]
+    fn file_modes_round_trip() {
+        let config = load(
+            r#"{ "files": { "/etc/secret": { "uid": 1000 }, "/bin/web": { "mode": "0750" } } }"#,
+        )
+        .unwrap();
+
+        let json = serde_json::to_string(&config).unwrap();
+        let config: Config = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(config.files["/etc/secret"].mode, None);
+        assert_eq!(config.files["/etc/secret"].uid, Some(1000));
+        assert_eq!(config.files["/bin/web"].mode, Some(0o750));
+        assert!(load(r#"{ "files": { "/bin/web": { "mode": "0999" } } }"#).is_err());
+    }
+}
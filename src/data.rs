@@ -0,0 +1,350 @@
+use crate::config::{Data, DataFilesystem};
+use crate::ext4;
+use crate::progress::{self, Unit};
+use crate::sparse;
+use crate::MiB;
+
+use anyhow::bail;
+use fatfs::{FatType, FormatVolumeOptions};
+use fscommon::StreamSlice;
+use std::fs::{self, File};
+use std::io::{self, prelude::*, SeekFrom};
+use std::path::Path;
+
+/// Default minimum data partition size when sizing images automatically.
+const DEFAULT_MIN_SIZE: u64 = (128 * MiB) as u64;
+
+impl DataFilesystem {
+    /// The MBR partition type, or `None` if the partition is left out.
+    pub fn partition_type(&self) -> Option<u8> {
+        match self {
+            Self::Ext4 | Self::F2fs | Self::Btrfs => Some(0x83),
+            Self::Vfat => Some(0xc),
+            Self::None => None,
+        }
+    }
+
+    /// The smallest partition the filesystem can be created on.
+    pub fn min_size(&self) -> u64 {
+        match self {
+            // The packer's formatter leaves out the journal on small partitions.
+            Self::Ext4 => MiB as u64,
+            // mkfs.f2fs needs room for its fixed number of segments, with some margin.
+            Self::F2fs => (64 * MiB) as u64,
+            // mkfs.btrfs refuses anything smaller.
+            Self::Btrfs => (109 * MiB) as u64,
+            // FAT32 needs at least 65525 clusters, fatfs uses 512 byte clusters for small volumes.
+            Self::Vfat => (33 * MiB) as u64,
+            Self::None => 0,
+        }
+    }
+
+    /// Estimates the partition size needed to store `used` bytes of files
+    /// in addition to the filesystem's own metadata.
+    fn with_overhead(&self, used: u64) -> u64 {
+        match self {
+            // Inode tables and the journal
+            Self::Ext4 => used + used / 8 + (64 * MiB) as u64,
+            // Overprovisioning and partially filled segments
+            Self::F2fs => used + used / 4 + (64 * MiB) as u64,
+            // Metadata is duplicated and space is allocated in chunks.
+            Self::Btrfs => used + used / 2 + (128 * MiB) as u64,
+            // File allocation tables
+            Self::Vfat => used + used / 16 + (16 * MiB) as u64,
+            Self::None => 0,
+        }
+    }
+
+    /// Identifies the filesystem on a partition by its magic numbers.
+    /// Returns `None` if it isn't one of the supported filesystems.
+    pub fn detect<R: Read + Seek>(partition: &mut R) -> io::Result<Option<Self>> {
+        let mut buf = vec![0; 0x10048];
+
+        partition.rewind()?;
+        let mut n = 0;
+        while n < buf.len() {
+            match partition.read(&mut buf[n..])? {
+                0 => break,
+                m => n += m,
+            }
+        }
+
+        let fs = if buf[1080..1082] == 0xEF53_u16.to_le_bytes() {
+            Some(Self::Ext4)
+        } else if buf[1024..1028] == 0xF2F52010_u32.to_le_bytes() {
+            Some(Self::F2fs)
+        } else if &buf[0x10040..0x10048] == b"_BHRfS_M" {
+            Some(Self::Btrfs)
+        } else if &buf[82..90] == b"FAT32   " && buf[510..512] == [0x55, 0xAA] {
+            Some(Self::Vfat)
+        } else {
+            None
+        };
+
+        Ok(fs)
+    }
+}
+
+/// Returns the size the data partition needs when sizing images automatically:
+/// the configured minimum, the smallest size the filesystem supports
+/// or the seed plus room for filesystem metadata, whichever is largest.
+pub fn min_size(data: &Data) -> anyhow::Result<u64> {
+    if data.filesystem.partition_type().is_none() {
+        return Ok(0);
+    }
+
+    let mut size = data
+        .min_size
+        .unwrap_or(DEFAULT_MIN_SIZE)
+        .max(data.filesystem.min_size());
+
+    if let Some(seed) = &data.seed {
+        let used = seed_size(Path::new(seed))?;
+        size = size.max(data.filesystem.with_overhead(used));
+    }
+
+    Ok(size)
+}
+
+/// Estimates the space a directory tree takes up on disk using 4 KiB blocks.
+fn seed_size(path: &Path) -> anyhow::Result<u64> {
+    let mut size = 4096;
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let meta = entry.path().symlink_metadata()?;
+
+        if meta.is_dir() {
+            size += seed_size(&entry.path())?;
+        } else {
+            size += meta.len().div_ceil(4096) * 4096;
+        }
+    }
+
+    Ok(size)
+}
+
+/// Formats the data partition. If `sparse` is set the partition already reads as zeros.
+pub fn format_data(
+    partition: &mut StreamSlice<File>,
+    data: &Data,
+    sparse: bool,
+) -> anyhow::Result<()> {
+    if let DataFilesystem::None = data.filesystem {
+        progress::info("Data partition disabled");
+        return Ok(());
+    }
+
+    if let Some(seed) = &data.seed {
+        if !Path::new(seed).is_dir() {
+            bail!("data seed {} is not a directory", seed);
+        }
+
+        progress::info(format!("Seeding data filesystem from {}", seed));
+    }
+
+    let phase = progress::phase("data", "Creating data filesystem...");
+
+    match data.filesystem {
+        DataFilesystem::Ext4 => format_ext4(partition, data, sparse)?,
+        DataFilesystem::F2fs => format_f2fs(partition, data, sparse)?,
+        DataFilesystem::Btrfs => format_btrfs(partition, data, sparse)?,
+        DataFilesystem::Vfat => format_vfat(partition, data)?,
+        DataFilesystem::None => {}
+    }
+
+    progress::info("Data filesystem created successfully");
+    phase.end();
+
+    Ok(())
+}
+
+fn format_ext4(partition: &mut StreamSlice<File>, data: &Data, sparse: bool) -> anyhow::Result<()> {
+    partition.seek(SeekFrom::End(0))?;
+    let size = partition.stream_position()?;
+
+    ext4::format(
+        partition,
+        size,
+        data.label.as_deref(),
+        data.uuid.as_deref(),
+        data.seed.as_deref().map(Path::new),
+        sparse,
+    )
+}
+
+fn format_f2fs(partition: &mut StreamSlice<File>, data: &Data, sparse: bool) -> anyhow::Result<()> {
+    let mut mkfs = crate::no_stdin("mkfs.f2fs");
+    mkfs.arg("-f");
+
+    if let Some(label) = &data.label {
+        mkfs.arg("-l").arg(label);
+    }
+    if let Some(uuid) = &data.uuid {
+        mkfs.arg("-U").arg(uuid);
+    }
+
+    with_copy(partition, sparse, |path| {
+        crate::run(mkfs.arg(path))?;
+
+        // mkfs.f2fs can't populate the filesystem itself.
+        if let Some(seed) = &data.seed {
+            let mut sload = crate::no_stdin("sload.f2fs");
+            sload.arg("-f").arg(seed).arg("-t").arg("/").arg(path);
+
+            crate::run(&mut sload)?;
+        }
+
+        Ok(())
+    })
+}
+
+fn format_btrfs(
+    partition: &mut StreamSlice<File>,
+    data: &Data,
+    sparse: bool,
+) -> anyhow::Result<()> {
+    let mut mkfs = crate::no_stdin("mkfs.btrfs");
+    mkfs.arg("-f");
+
+    if let Some(label) = &data.label {
+        mkfs.arg("-L").arg(label);
+    }
+    if let Some(uuid) = &data.uuid {
+        mkfs.arg("-U").arg(uuid);
+    }
+    if let Some(seed) = &data.seed {
+        mkfs.arg("--rootdir").arg(seed);
+    }
+
+    with_copy(partition, sparse, |path| crate::run(mkfs.arg(path)))
+}
+
+fn format_vfat(partition: &mut StreamSlice<File>, data: &Data) -> anyhow::Result<()> {
+    let mut format_opts = FormatVolumeOptions::new().fat_type(FatType::Fat32);
+
+    if let Some(label) = &data.label {
+        if label.len() > 11 || !label.is_ascii() {
+            bail!("vfat labels are limited to 11 ASCII characters");
+        }
+
+        let mut volume_label = [b' '; 11];
+        volume_label[..label.len()].copy_from_slice(label.to_ascii_uppercase().as_bytes());
+
+        format_opts = format_opts.volume_label(volume_label);
+    }
+
+    if let Some(uuid) = &data.uuid {
+        let volume_id = u32::from_str_radix(&uuid.replace('-', ""), 16)
+            .map_err(|_| anyhow::anyhow!("vfat UUIDs have the format XXXX-XXXX"))?;
+
+        format_opts = format_opts.volume_id(volume_id);
+    }
+
+    partition.rewind()?;
+    fatfs::format_volume(&mut *partition, format_opts)?;
+
+    if let Some(seed) = &data.seed {
+        partition.rewind()?;
+
+        let fs = fatfs::FileSystem::new(&mut *partition, fatfs::FsOptions::new())?;
+        copy_to_fat(Path::new(seed), &fs.root_dir())?;
+    }
+
+    Ok(())
+}
+
+/// Copies a host directory into a FAT directory. Ownership and modes can't be represented.
+fn copy_to_fat<T: fatfs::ReadWriteSeek>(src: &Path, dst: &fatfs::Dir<T>) -> anyhow::Result<()> {
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => bail!("non-UTF-8 file name in data seed: {:?}", name),
+        };
+
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            copy_to_fat(&entry.path(), &dst.create_dir(name)?)?;
+        } else if file_type.is_file() {
+            let mut file = dst.create_file(name)?;
+            io::copy(&mut File::open(entry.path())?, &mut file)?;
+        } else {
+            bail!("vfat data seed can only contain files and directories");
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `f` on a temporary file of the partition's size and writes the result back.
+/// The mkfs tools overwrite everything they need, so the old contents aren't copied in.
+fn with_copy<F>(partition: &mut StreamSlice<File>, sparse: bool, f: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&Path) -> anyhow::Result<()>,
+{
+    partition.seek(SeekFrom::End(0))?;
+    let size = partition.stream_position()?;
+
+    let mut tmp_file = tempfile::NamedTempFile::new()?;
+    tmp_file.as_file().set_len(size)?;
+
+    f(tmp_file.path())?;
+
+    tmp_file.rewind()?;
+    partition.rewind()?;
+
+    let mut src = progress::bar("Writing data filesystem", size, Unit::Bytes).wrap(tmp_file);
+    sparse::copy(&mut src, partition, sparse)?;
+    src.finish();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(filesystem: DataFilesystem, min_size: Option<u64>, seed: Option<&Path>) -> Data {
+        Data {
+            filesystem,
+            label: None,
+            uuid: None,
+            seed: seed.map(|seed| seed.to_string_lossy().into_owned()),
+            min_size,
+        }
+    }
+
+    #[test]
+    fn min_size_respects_filesystem_minimum() {
+        assert_eq!(
+            min_size(&data(DataFilesystem::Btrfs, Some((16 * MiB) as u64), None)).unwrap(),
+            (109 * MiB) as u64
+        );
+        assert_eq!(
+            min_size(&data(DataFilesystem::Ext4, Some((16 * MiB) as u64), None)).unwrap(),
+            (16 * MiB) as u64
+        );
+        assert_eq!(
+            min_size(&data(DataFilesystem::None, Some((16 * MiB) as u64), None)).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn min_size_includes_seed() {
+        let seed = tempfile::tempdir().unwrap();
+        File::create(seed.path().join("big"))
+            .unwrap()
+            .set_len((200 * MiB) as u64)
+            .unwrap();
+
+        let ext4 = min_size(&data(DataFilesystem::Ext4, None, Some(seed.path()))).unwrap();
+        let btrfs = min_size(&data(DataFilesystem::Btrfs, None, Some(seed.path()))).unwrap();
+
+        assert!(ext4 > (200 * MiB) as u64 + (64 * MiB) as u64);
+        assert!(btrfs > ext4);
+    }
+}
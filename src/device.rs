@@ -0,0 +1,186 @@
+use anyhow::bail;
+use nix::sys::stat::{major, minor};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, prelude::*};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+/// A block device as described by sysfs.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    /// Kernel name, e.g. sdb or mmcblk0.
+    pub name: String,
+    pub size: u64,
+    pub vendor: Option<String>,
+    pub model: Option<String>,
+    /// Directory of the device in /sys/dev/block.
+    sysfs: PathBuf,
+}
+
+impl Identity {
+    /// Returns vendor, model and kernel name, e.g. "SanDisk Ultra (sdb)".
+    pub fn description(&self) -> String {
+        let model: Vec<&str> = [&self.vendor, &self.model]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect();
+
+        if model.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{} ({})", model.join(" "), self.name)
+        }
+    }
+
+    /// Fails if the device or one of its partitions is mounted
+    /// or used by another block device, e.g. LVM or dm-crypt.
+    pub fn check_unused(&self) -> anyhow::Result<()> {
+        // Kernel names of the device and its partitions by device number, e.g. "8:17" => sdb1.
+        let mut devices = BTreeMap::new();
+
+        let mut dirs = vec![self.sysfs.clone()];
+        for entry in fs::read_dir(&self.sysfs)? {
+            let path = entry?.path();
+
+            if path.join("partition").exists() {
+                dirs.push(path);
+            }
+        }
+
+        for dir in dirs {
+            let name = dir
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+
+            if let Ok(holders) = fs::read_dir(dir.join("holders")) {
+                if let Some(holder) = holders.flatten().next() {
+                    bail!(
+                        "{} is in use by {}",
+                        name,
+                        holder.file_name().to_string_lossy()
+                    );
+                }
+            }
+
+            if let Some(dev) = attribute(&dir.join("dev")) {
+                devices.insert(dev, name);
+            }
+        }
+
+        // Mounts by the kernel name of their device.
+        let mut mounts = Vec::new();
+
+        for line in fs::read_to_string("/proc/self/mountinfo")?.lines() {
+            // ID, parent ID, major:minor, root, mount point, options, optional fields,
+            // separator, filesystem type, source, superblock options
+            let fields: Vec<&str> = line.split(' ').collect();
+
+            let (dev, mount_point) = match (fields.get(2), fields.get(4)) {
+                (Some(dev), Some(mount_point)) => (*dev, *mount_point),
+                _ => continue,
+            };
+
+            // Filesystems like btrfs report an anonymous device number,
+            // so the source has to be checked too.
+            let source = fields
+                .iter()
+                .position(|field| *field == "-")
+                .and_then(|i| fields.get(i + 2))
+                .filter(|source| source.starts_with("/dev/"))
+                .and_then(|source| fs::canonicalize(source).ok())
+                .and_then(|source| Some(source.file_name()?.to_string_lossy().into_owned()));
+
+            let name = match devices.get(dev) {
+                Some(name) => name.clone(),
+                None => match source {
+                    Some(source) if devices.values().any(|name| *name == source) => source,
+                    _ => continue,
+                },
+            };
+
+            mounts.push((name, mount_point.replace("\\040", " ")));
+        }
+
+        if let Some((name, _)) = mounts.iter().find(|(_, mount_point)| mount_point == "/") {
+            bail!("{} holds the root filesystem of this system", name);
+        }
+
+        if let Some((name, mount_point)) = mounts.first() {
+            bail!("{} is mounted at {}, unmount it first", name, mount_point);
+        }
+
+        Ok(())
+    }
+}
+
+/// Asks the user to confirm that `path` may be overwritten.
+pub fn confirm(path: &str, device: &Identity) -> anyhow::Result<()> {
+    eprintln!(
+        "About to overwrite {}: {}, {} bytes. All data on it will be lost.",
+        path,
+        device.description(),
+        device.size
+    );
+    eprint!("Continue? [y/N] ");
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => bail!("not confirmed, pass --yes to skip the question"),
+    }
+}
+
+/// Returns the identity of the block device at `path`,
+/// or `None` if it doesn't exist or isn't a block device.
+/// The device itself isn't opened.
+pub fn identify(path: &str) -> anyhow::Result<Option<Identity>> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    if !metadata.file_type().is_block_device() {
+        return Ok(None);
+    }
+
+    let rdev = metadata.rdev();
+    let sysfs = fs::canonicalize(format!("/sys/dev/block/{}:{}", major(rdev), minor(rdev)))?;
+
+    let name = sysfs
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    // sysfs always counts 512 byte sectors.
+    let size = match attribute(&sysfs.join("size")).map(|size| size.parse::<u64>()) {
+        Some(Ok(sectors)) => sectors * 512,
+        _ => bail!("can't determine the size of {}", path),
+    };
+
+    Ok(Some(Identity {
+        name,
+        size,
+        vendor: attribute(&sysfs.join("device/vendor")),
+        model: attribute(&sysfs.join("device/model")),
+        sysfs,
+    }))
+}
+
+/// Reads a sysfs attribute, treating empty values as missing.
+fn attribute(path: &Path) -> Option<String> {
+    let value = fs::read_to_string(path).ok()?;
+    let value = value.trim();
+
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_owned())
+    }
+}
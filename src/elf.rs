@@ -0,0 +1,72 @@
+use anyhow::bail;
+
+/// The ELF machine type of binaries for `arch`.
+pub fn machine(arch: &str) -> Option<u16> {
+    match arch {
+        "x86_64" => Some(62),
+        "rpi" => Some(183), // aarch64
+        _ => None,
+    }
+}
+
+fn machine_name(machine: u16) -> String {
+    match machine {
+        3 => String::from("x86"),
+        40 => String::from("arm"),
+        62 => String::from("x86_64"),
+        183 => String::from("aarch64"),
+        _ => format!("machine type {}", machine),
+    }
+}
+
+/// Checks that `buf` starts with the header of a 64-bit little-endian
+/// ELF executable that runs on `arch`.
+pub fn check(buf: &[u8], arch: &str) -> anyhow::Result<()> {
+    if buf.len() < 64 || buf[0..4] != *b"\x7fELF" {
+        bail!("not an ELF binary");
+    }
+
+    if buf[4] != 2 || buf[5] != 1 {
+        bail!("not a 64-bit little-endian ELF binary");
+    }
+
+    match u16::from_le_bytes([buf[16], buf[17]]) {
+        2 | 3 => {} // ET_EXEC, ET_DYN (static-pie)
+        _ => bail!("not an executable"),
+    }
+
+    let machine = u16::from_le_bytes([buf[18], buf[19]]);
+    if Some(machine) != self::machine(arch) {
+        bail!("built for {} instead of {}", machine_name(machine), arch);
+    }
+
+    Ok(())
+}
+
+/// Checks that the ELF binary in `buf` doesn't need a dynamic loader.
+/// Static PIE binaries relocate themselves and are accepted.
+pub fn check_static(buf: &[u8]) -> anyhow::Result<()> {
+    const PT_INTERP: u32 = 3;
+
+    if buf.len() < 64 {
+        bail!("not an ELF binary");
+    }
+
+    let phoff = u64::from_le_bytes(buf[32..40].try_into()?) as usize;
+    let phentsize = u16::from_le_bytes([buf[54], buf[55]]) as usize;
+    let phnum = u16::from_le_bytes([buf[56], buf[57]]) as usize;
+
+    for i in 0..phnum {
+        let start = phoff.saturating_add(i * phentsize);
+        let header = match buf.get(start..start.saturating_add(4)) {
+            Some(header) => header,
+            None => bail!("truncated program headers"),
+        };
+
+        if u32::from_le_bytes(header.try_into()?) == PT_INTERP {
+            bail!("dynamically linked, only static binaries can run without a C library");
+        }
+    }
+
+    Ok(())
+}
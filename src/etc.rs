@@ -0,0 +1,329 @@
+use crate::config::Config;
+use crate::rootfs::{Contents, Entry, Rootfs};
+
+use anyhow::bail;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::path::Path;
+
+// There is no shell in the image.
+const NOLOGIN: &str = "/sbin/nologin";
+
+const NSSWITCH_CONF: &str = "passwd: files
+group: files
+shadow: files
+hosts: files dns
+networks: files
+protocols: files
+services: files
+";
+
+/// Adds a generated /etc directory to the root filesystem.
+pub fn add_etc(rootfs: &mut Rootfs, config: &Config, arch: &str) -> anyhow::Result<()> {
+    let mut files = BTreeMap::new();
+
+    files.insert("hostname", format!("{}\n", hostname(config)?));
+    files.insert("hosts", hosts(config)?);
+    files.insert("passwd", passwd(config)?);
+    files.insert("group", group(config)?);
+    files.insert("nsswitch.conf", String::from(NSSWITCH_CONF));
+    files.insert("os-release", os_release(arch));
+
+    if !config.nameservers.is_empty() {
+        files.insert("resolv.conf", resolv_conf(config));
+    }
+
+    rootfs.insert("/etc", Entry::dir())?;
+
+    for (name, contents) in files {
+        rootfs.insert(
+            Path::new("/etc").join(name),
+            Entry::file(Contents::Bytes(contents.into_bytes())),
+        )?;
+    }
+
+    if config.nameservers.is_empty() {
+        rootfs.insert("/etc/resolv.conf", Entry::symlink("/run/resolv.conf"))?;
+    }
+
+    Ok(())
+}
+
+fn hostname(config: &Config) -> anyhow::Result<&str> {
+    let hostname = config.hostname.as_str();
+
+    if hostname.is_empty()
+        || hostname.len() > 64
+        || hostname.starts_with('-')
+        || !hostname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+    {
+        bail!("invalid hostname: {}", hostname);
+    }
+
+    Ok(hostname)
+}
+
+fn hosts(config: &Config) -> anyhow::Result<String> {
+    let hostname = hostname(config)?;
+
+    let mut hosts = String::new();
+    writeln!(hosts, "127.0.0.1\tlocalhost")?;
+    writeln!(hosts, "::1\tlocalhost ip6-localhost ip6-loopback")?;
+    writeln!(hosts, "127.0.1.1\t{}", hostname)?;
+
+    Ok(hosts)
+}
+
+fn resolv_conf(config: &Config) -> String {
+    let mut resolv_conf = String::new();
+
+    for nameserver in &config.nameservers {
+        resolv_conf += &format!("nameserver {}\n", nameserver);
+    }
+
+    resolv_conf
+}
+
+/// Returns all groups of the image (including root) sorted by gid.
+fn groups(config: &Config) -> anyhow::Result<BTreeMap<u32, String>> {
+    let mut groups = BTreeMap::new();
+    groups.insert(0, String::from("root"));
+
+    for group in &config.groups {
+        if groups.values().any(|name| *name == group.name) {
+            bail!("duplicate group name: {}", group.name);
+        }
+
+        if groups.insert(group.gid, group.name.clone()).is_some() {
+            bail!("duplicate gid: {}", group.gid);
+        }
+    }
+
+    // Users without an explicit primary group get their own.
+    for user in &config.users {
+        let gid = user.gid.unwrap_or(user.uid);
+
+        if !groups.contains_key(&gid) {
+            if groups.values().any(|name| *name == user.name) {
+                bail!(
+                    "user {} needs a group with gid {} but its name is taken",
+                    user.name,
+                    gid
+                );
+            }
+
+            groups.insert(gid, user.name.clone());
+        }
+    }
+
+    Ok(groups)
+}
+
+fn passwd(config: &Config) -> anyhow::Result<String> {
+    let mut names = HashSet::new();
+    let mut uids = HashSet::new();
+
+    names.insert("root");
+    uids.insert(0);
+
+    let mut passwd = String::new();
+    writeln!(passwd, "root:x:0:0:root:/data:{}", NOLOGIN)?;
+
+    for user in &config.users {
+        if user.name.is_empty() || user.name.contains([':', '\n']) {
+            bail!("invalid user name: {:?}", user.name);
+        }
+
+        if !names.insert(&user.name) {
+            bail!("duplicate user name: {}", user.name);
+        }
+
+        if !uids.insert(user.uid) {
+            bail!("duplicate uid: {}", user.uid);
+        }
+
+        writeln!(
+            passwd,
+            "{}:x:{}:{}:{}:{}:{}",
+            user.name,
+            user.uid,
+            user.gid.unwrap_or(user.uid),
+            user.name,
+            user.home,
+            NOLOGIN
+        )?;
+    }
+
+    Ok(passwd)
+}
+
+fn group(config: &Config) -> anyhow::Result<String> {
+    let groups = groups(config)?;
+
+    let mut members: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+
+    for user in &config.users {
+        for group in &user.groups {
+            if !groups.values().any(|name| name == group) {
+                bail!("user {} is a member of unknown group {}", user.name, group);
+            }
+
+            members.entry(group).or_default().push(&user.name);
+        }
+    }
+
+    let mut group = String::new();
+
+    for (gid, name) in &groups {
+        if name.is_empty() || name.contains([':', '\n']) {
+            bail!("invalid group name: {:?}", name);
+        }
+
+        let members = members
+            .get(name.as_str())
+            .map(|members| members.join(","))
+            .unwrap_or_default();
+
+        writeln!(group, "{}:x:{}:{}", name, gid, members)?;
+    }
+
+    Ok(group)
+}
+
+fn os_release(arch: &str) -> String {
+    format!(
+        "NAME=\"rustkrazy\"
+ID=rustkrazy
+PRETTY_NAME=\"rustkrazy ({arch})\"
+VARIANT_ID={arch}
+BUILD_ID=\"rustkrazy_packer {version}\"
+HOME_URL=\"https://github.com/rustkrazy\"
+",
+        arch = arch,
+        version = env!("CARGO_PKG_VERSION"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Group, User};
+    use crate::rootfs::Kind;
+
+    fn user(name: &str, uid: u32, gid: Option<u32>, groups: &[&str]) -> User {
+        User {
+            name: name.to_owned(),
+            uid,
+            gid,
+            groups: groups.iter().map(|group| group.to_string()).collect(),
+            home: String::from("/data"),
+        }
+    }
+
+    fn group(name: &str, gid: u32) -> Group {
+        Group {
+            name: name.to_owned(),
+            gid,
+        }
+    }
+
+    #[test]
+    fn users_and_groups() {
+        let config = Config {
+            users: vec![
+                user("web", 1000, None, &["net"]),
+                user("db", 1001, Some(2000), &["net"]),
+            ],
+            groups: vec![group("net", 2000)],
+            ..Config::default()
+        };
+
+        assert_eq!(
+            passwd(&config).unwrap(),
+            "root:x:0:0:root:/data:/sbin/nologin
+web:x:1000:1000:web:/data:/sbin/nologin
+db:x:1001:2000:db:/data:/sbin/nologin
+"
+        );
+        assert_eq!(
+            super::group(&config).unwrap(),
+            "root:x:0:
+web:x:1000:
+net:x:2000:web,db
+"
+        );
+    }
+
+    #[test]
+    fn invalid_users_and_groups() {
+        let invalid = [
+            Config {
+                users: vec![user("a", 1000, None, &[]), user("b", 1000, None, &[])],
+                ..Config::default()
+            },
+            Config {
+                users: vec![user("root", 1000, None, &[])],
+                ..Config::default()
+            },
+            Config {
+                users: vec![user("a", 1000, None, &["missing"])],
+                ..Config::default()
+            },
+            Config {
+                groups: vec![group("a", 1000), group("b", 1000)],
+                ..Config::default()
+            },
+            Config {
+                users: vec![user("a", 1000, None, &[])],
+                groups: vec![group("a", 2000)],
+                ..Config::default()
+            },
+            Config {
+                users: vec![user("a:b", 1000, None, &[])],
+                ..Config::default()
+            },
+        ];
+
+        for config in invalid {
+            assert!(
+                passwd(&config).is_err() || super::group(&config).is_err(),
+                "{:?}",
+                config
+            );
+        }
+    }
+
+    #[test]
+    fn hostname_validation() {
+        for valid in ["rustkrazy", "gw-1.lan"] {
+            let config = Config {
+                hostname: valid.to_owned(),
+                ..Config::default()
+            };
+            assert!(hostname(&config).is_ok(), "{}", valid);
+        }
+
+        for invalid in ["", "-gw", "gw_1", "gw 1"] {
+            let config = Config {
+                hostname: invalid.to_owned(),
+                ..Config::default()
+            };
+            assert!(hostname(&config).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn resolv_conf_fallback() {
+        let mut rootfs = Rootfs::new();
+        add_etc(&mut rootfs, &Config::default(), "x86_64").unwrap();
+
+        let entries: BTreeMap<_, _> = rootfs.entries().collect();
+        assert!(matches!(
+            &entries[Path::new("/etc/resolv.conf")].kind,
+            Kind::Symlink(target) if target == Path::new("/run/resolv.conf")
+        ));
+        assert_eq!(entries[Path::new("/etc/passwd")].mode, 0o644);
+    }
+}
@@ -0,0 +1,1045 @@
+//! A minimal ext4 formatter.
+//!
+//! The filesystem always uses 4 KiB blocks, 256 byte inodes and the features
+//! has_journal, filetype, extents, sparse_super, large_file, dir_nlink and extra_isize.
+//! Newer features like metadata checksums or flex_bg are left out
+//! so that older kernels can mount it. Timestamps are zero,
+//! making the output deterministic.
+
+use crate::progress;
+use crate::sparse;
+
+use anyhow::{anyhow, bail};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, prelude::*, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+const BLOCK_SIZE: u32 = 4096;
+const BLOCKS_PER_GROUP: u32 = 8 * BLOCK_SIZE;
+const INODE_SIZE: u32 = 256;
+const INODE_RATIO: u32 = 16384;
+const EXTRA_ISIZE: u16 = 32;
+
+const ROOT_INO: u32 = 2;
+const JOURNAL_INO: u32 = 8;
+const LOST_AND_FOUND_INO: u32 = 11;
+const FIRST_INO: u32 = 11;
+
+const COMPAT_HAS_JOURNAL: u32 = 0x4;
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+const RO_COMPAT_DIR_NLINK: u32 = 0x20;
+const RO_COMPAT_EXTRA_ISIZE: u32 = 0x40;
+
+const EXTENTS_FL: u32 = 0x80000;
+const EXTENT_MAGIC: u16 = 0xF30A;
+const MAX_LEAF_EXTENTS: usize = (BLOCK_SIZE as usize - 12) / 12;
+
+const S_IFDIR: u16 = 0o040000;
+const S_IFREG: u16 = 0o100000;
+const S_IFLNK: u16 = 0o120000;
+
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_SYMLINK: u8 = 7;
+
+const JBD2_MAGIC: u32 = 0xC03B3998;
+const JBD2_SUPERBLOCK_V2: u32 = 4;
+
+/// Formats `dev` (of `size` bytes) as ext4, optionally copying the contents
+/// of the host directory `seed` into it with their ownership and modes.
+/// If `sparse` is set `dev` already reads as zeros and zero blocks are skipped.
+pub fn format<W: Write + Seek>(
+    dev: &mut W,
+    size: u64,
+    label: Option<&str>,
+    uuid: Option<&str>,
+    seed: Option<&Path>,
+    sparse: bool,
+) -> anyhow::Result<()> {
+    let layout = Layout::new(size)?;
+
+    let label = match label {
+        Some(label) if label.len() > 16 => bail!("ext4 labels are limited to 16 bytes"),
+        Some(label) => label.as_bytes(),
+        None => &[],
+    };
+
+    let uuid = match uuid {
+        Some(uuid) => parse_uuid(uuid)?,
+        None => derive_uuid(layout.blocks_count, label),
+    };
+
+    let mut tree = Tree::new();
+    tree.build(seed)?;
+
+    if tree.inodes.keys().next_back().copied().unwrap_or(0) > layout.inodes_count() {
+        bail!("data seed has more files than the data filesystem has inodes");
+    }
+
+    let mut alloc = Allocator::new(&layout);
+
+    let journal_blocks = journal_blocks(layout.blocks_count);
+    if journal_blocks > 0 {
+        tree.inodes.insert(
+            JOURNAL_INO,
+            Inode {
+                mode: S_IFREG | 0o600,
+                uid: 0,
+                gid: 0,
+                links: 1,
+                data: Data::Journal(journal_blocks),
+            },
+        );
+    }
+
+    let mut placed = BTreeMap::new();
+    for (ino, inode) in &tree.inodes {
+        placed.insert(*ino, alloc.place(inode)?);
+    }
+
+    let journal_iblock = placed.get(&JOURNAL_INO).map(|placement| placement.iblock);
+
+    progress::detail(format!(
+        "Data filesystem: {} blocks in {} groups, {} inodes",
+        layout.blocks_count,
+        layout.groups,
+        layout.inodes_count()
+    ));
+
+    let max_ino = *tree.inodes.keys().next_back().unwrap();
+
+    let mut group_descs = Vec::new();
+    let mut free_blocks = 0;
+    let mut free_inodes = 0;
+
+    for group in 0..layout.groups {
+        let used_inodes = max_ino
+            .saturating_sub(group * layout.inodes_per_group)
+            .min(layout.inodes_per_group);
+        let used_dirs = tree
+            .inodes
+            .iter()
+            .filter(|(ino, inode)| {
+                (*ino - 1) / layout.inodes_per_group == group && matches!(inode.data, Data::Dir(_))
+            })
+            .count() as u32;
+        let used_blocks = layout.overhead(group) + alloc.used[group as usize];
+
+        let group_free_blocks = layout.group_len(group) - used_blocks;
+        let group_free_inodes = layout.inodes_per_group - used_inodes;
+
+        free_blocks += group_free_blocks;
+        free_inodes += group_free_inodes;
+
+        let mut desc = [0; 32];
+        put32(&mut desc, 0, layout.block_bitmap(group));
+        put32(&mut desc, 4, layout.inode_bitmap(group));
+        put32(&mut desc, 8, layout.inode_table(group));
+        put16(&mut desc, 12, group_free_blocks as u16);
+        put16(&mut desc, 14, group_free_inodes as u16);
+        put16(&mut desc, 16, used_dirs as u16);
+        group_descs.extend_from_slice(&desc);
+
+        write_at(
+            dev,
+            layout.block_bitmap(group),
+            &bitmap(used_blocks, layout.group_len(group)),
+        )?;
+        write_at(
+            dev,
+            layout.inode_bitmap(group),
+            &bitmap(used_inodes, layout.inodes_per_group),
+        )?;
+
+        dev.seek(SeekFrom::Start(
+            layout.inode_table(group) as u64 * BLOCK_SIZE as u64,
+        ))?;
+        sparse::zero(
+            dev,
+            layout.inode_table_blocks as u64 * BLOCK_SIZE as u64,
+            sparse,
+        )?;
+    }
+
+    group_descs.resize((layout.gdt_blocks * BLOCK_SIZE) as usize, 0);
+
+    for group in (0..layout.groups).filter(|group| layout.has_super(*group)) {
+        let sb = Superblock {
+            layout: &layout,
+            group,
+            free_blocks,
+            free_inodes,
+            uuid,
+            label,
+            journal: journal_iblock.map(|iblock| (iblock, journal_blocks)),
+        };
+
+        let mut block = vec![0; BLOCK_SIZE as usize];
+        let offset = if group == 0 { 1024 } else { 0 };
+        block[offset..offset + 1024].copy_from_slice(&sb.to_bytes());
+
+        write_at(dev, layout.group_start(group), &block)?;
+        write_at(dev, layout.group_start(group) + 1, &group_descs)?;
+    }
+
+    for (ino, inode) in &tree.inodes {
+        let placement = &placed[ino];
+
+        let offset = layout.inode_table((ino - 1) / layout.inodes_per_group) as u64
+            * BLOCK_SIZE as u64
+            + ((ino - 1) % layout.inodes_per_group) as u64 * INODE_SIZE as u64;
+
+        dev.seek(SeekFrom::Start(offset))?;
+        dev.write_all(&inode.to_bytes(placement))?;
+
+        if let Some((block, leaf)) = &placement.leaf {
+            write_at(dev, *block, leaf)?;
+        }
+
+        let mut blocks = placement
+            .extents
+            .iter()
+            .flat_map(|extent| extent.start..extent.start + extent.len);
+
+        match &inode.data {
+            Data::Dir(entries) => {
+                for chunk in dir_blocks(entries).chunks(BLOCK_SIZE as usize) {
+                    write_at(dev, blocks.next().unwrap(), chunk)?;
+                }
+            }
+            Data::File(src, _) => {
+                let mut file = File::open(src)?;
+                let mut buf = vec![0; BLOCK_SIZE as usize];
+
+                for block in blocks {
+                    buf.fill(0);
+
+                    let mut n = 0;
+                    while n < buf.len() {
+                        match file.read(&mut buf[n..])? {
+                            0 => break,
+                            m => n += m,
+                        }
+                    }
+
+                    if !sparse || buf.iter().any(|b| *b != 0) {
+                        write_at(dev, block, &buf)?;
+                    }
+                }
+            }
+            Data::Symlink(target) if target.len() >= 60 => {
+                let mut buf = target.clone();
+                buf.resize(BLOCK_SIZE as usize, 0);
+
+                write_at(dev, blocks.next().unwrap(), &buf)?;
+            }
+            Data::Symlink(_) => {}
+            Data::Journal(len) => {
+                let mut first = true;
+                for block in blocks {
+                    if first {
+                        write_at(dev, block, &journal_superblock(*len, uuid))?;
+                        first = false;
+                    } else if !sparse {
+                        write_at(dev, block, &[0; BLOCK_SIZE as usize])?;
+                    }
+                }
+            }
+        }
+    }
+
+    dev.flush()?;
+    Ok(())
+}
+
+struct Layout {
+    blocks_count: u32,
+    groups: u32,
+    inodes_per_group: u32,
+    inode_table_blocks: u32,
+    gdt_blocks: u32,
+}
+
+impl Layout {
+    fn new(size: u64) -> anyhow::Result<Self> {
+        let mut blocks_count: u32 = (size / BLOCK_SIZE as u64)
+            .try_into()
+            .map_err(|_| anyhow!("data partition is too large for ext4 without 64bit"))?;
+
+        loop {
+            if blocks_count == 0 {
+                bail!("data partition is too small for ext4");
+            }
+
+            let groups = blocks_count.div_ceil(BLOCKS_PER_GROUP);
+            let inodes_per_group = (blocks_count.min(BLOCKS_PER_GROUP)
+                / (INODE_RATIO / BLOCK_SIZE))
+                .next_multiple_of(BLOCK_SIZE / INODE_SIZE)
+                .max(BLOCK_SIZE / INODE_SIZE);
+
+            let layout = Self {
+                blocks_count,
+                groups,
+                inodes_per_group,
+                inode_table_blocks: inodes_per_group / (BLOCK_SIZE / INODE_SIZE),
+                gdt_blocks: (groups * 32).div_ceil(BLOCK_SIZE),
+            };
+
+            // Like mke2fs, drop a last group that is too small to be useful.
+            let last = groups - 1;
+            if groups > 1 && layout.group_len(last) < layout.overhead(last) + 64 {
+                blocks_count -= layout.group_len(last);
+                continue;
+            }
+
+            if layout.group_len(0) < layout.overhead(0) + 64 {
+                bail!("data partition is too small for ext4");
+            }
+
+            return Ok(layout);
+        }
+    }
+
+    fn inodes_count(&self) -> u32 {
+        self.groups * self.inodes_per_group
+    }
+
+    /// With sparse_super only groups 0, 1 and powers of 3, 5 and 7 hold superblock backups.
+    fn has_super(&self, group: u32) -> bool {
+        fn is_power_of(n: u32, base: u32) -> bool {
+            let mut power = 1;
+            while power < n as u64 {
+                power *= base as u64;
+            }
+
+            power == n as u64
+        }
+
+        group <= 1 || is_power_of(group, 3) || is_power_of(group, 5) || is_power_of(group, 7)
+    }
+
+    fn group_start(&self, group: u32) -> u32 {
+        group * BLOCKS_PER_GROUP
+    }
+
+    fn group_len(&self, group: u32) -> u32 {
+        ((group + 1) * BLOCKS_PER_GROUP).min(self.blocks_count) - self.group_start(group)
+    }
+
+    fn block_bitmap(&self, group: u32) -> u32 {
+        self.group_start(group)
+            + if self.has_super(group) {
+                1 + self.gdt_blocks
+            } else {
+                0
+            }
+    }
+
+    fn inode_bitmap(&self, group: u32) -> u32 {
+        self.block_bitmap(group) + 1
+    }
+
+    fn inode_table(&self, group: u32) -> u32 {
+        self.block_bitmap(group) + 2
+    }
+
+    fn data_start(&self, group: u32) -> u32 {
+        self.inode_table(group) + self.inode_table_blocks
+    }
+
+    /// Number of metadata blocks at the start of a group.
+    fn overhead(&self, group: u32) -> u32 {
+        self.data_start(group) - self.group_start(group)
+    }
+}
+
+struct Superblock<'a> {
+    layout: &'a Layout,
+    group: u32,
+    free_blocks: u32,
+    free_inodes: u32,
+    uuid: [u8; 16],
+    label: &'a [u8],
+    journal: Option<([u8; 60], u32)>,
+}
+
+impl Superblock<'_> {
+    fn to_bytes(&self) -> [u8; 1024] {
+        let layout = self.layout;
+        let mut sb = [0; 1024];
+
+        put32(&mut sb, 0, layout.inodes_count());
+        put32(&mut sb, 4, layout.blocks_count);
+        put32(&mut sb, 12, self.free_blocks);
+        put32(&mut sb, 16, self.free_inodes);
+        put32(&mut sb, 20, 0); // first data block
+        put32(&mut sb, 24, 2); // 1024 << 2 = 4096
+        put32(&mut sb, 28, 2);
+        put32(&mut sb, 32, BLOCKS_PER_GROUP);
+        put32(&mut sb, 36, BLOCKS_PER_GROUP);
+        put32(&mut sb, 40, layout.inodes_per_group);
+        put16(&mut sb, 54, 0xFFFF); // no mount count checks
+        put16(&mut sb, 56, 0xEF53);
+        put16(&mut sb, 58, 1); // clean
+        put16(&mut sb, 60, 1); // continue on errors
+        put32(&mut sb, 76, 1); // dynamic revision
+        put32(&mut sb, 84, FIRST_INO);
+        put16(&mut sb, 88, INODE_SIZE as u16);
+        put16(&mut sb, 90, self.group as u16);
+        put32(
+            &mut sb,
+            92,
+            if self.journal.is_some() {
+                COMPAT_HAS_JOURNAL
+            } else {
+                0
+            },
+        );
+        put32(&mut sb, 96, INCOMPAT_FILETYPE | INCOMPAT_EXTENTS);
+        put32(
+            &mut sb,
+            100,
+            RO_COMPAT_SPARSE_SUPER
+                | RO_COMPAT_LARGE_FILE
+                | RO_COMPAT_DIR_NLINK
+                | RO_COMPAT_EXTRA_ISIZE,
+        );
+        sb[104..120].copy_from_slice(&self.uuid);
+        sb[120..120 + self.label.len()].copy_from_slice(self.label);
+
+        // Hash seed for directory indexes, unused but expected to be set.
+        let mut hash_seed = self.uuid;
+        hash_seed.reverse();
+        sb[236..252].copy_from_slice(&hash_seed);
+
+        if let Some((iblock, len)) = self.journal {
+            put32(&mut sb, 224, JOURNAL_INO);
+            sb[253] = 1; // s_jnl_blocks holds a copy of the journal inode's i_block
+            sb[268..328].copy_from_slice(&iblock);
+            put32(&mut sb, 332, len * BLOCK_SIZE);
+        }
+
+        put16(&mut sb, 348, EXTRA_ISIZE);
+        put16(&mut sb, 350, EXTRA_ISIZE);
+
+        sb
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Extent {
+    start: u32,
+    len: u32,
+}
+
+enum Data {
+    Dir(Vec<DirEntry>),
+    File(PathBuf, u64),
+    Symlink(Vec<u8>),
+    Journal(u32),
+}
+
+struct DirEntry {
+    name: Vec<u8>,
+    ino: u32,
+    file_type: u8,
+}
+
+struct Inode {
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    links: u16,
+    data: Data,
+}
+
+struct Placement {
+    extents: Vec<Extent>,
+    /// The inode's i_block: an extent tree root or a fast symlink target.
+    iblock: [u8; 60],
+    /// Extent tree leaf for inodes with more than four extents.
+    leaf: Option<(u32, Vec<u8>)>,
+}
+
+impl Inode {
+    fn size(&self) -> u64 {
+        match &self.data {
+            Data::Dir(entries) => dir_blocks(entries).len() as u64,
+            Data::File(_, size) => *size,
+            Data::Symlink(target) => target.len() as u64,
+            Data::Journal(len) => *len as u64 * BLOCK_SIZE as u64,
+        }
+    }
+
+    fn to_bytes(&self, placement: &Placement) -> [u8; INODE_SIZE as usize] {
+        let mut inode = [0; INODE_SIZE as usize];
+
+        let size = self.size();
+        let mut blocks: u64 = placement.extents.iter().map(|e| e.len as u64).sum();
+        if placement.leaf.is_some() {
+            blocks += 1;
+        }
+        let sectors = blocks * (BLOCK_SIZE / 512) as u64;
+
+        let fast_symlink = matches!(&self.data, Data::Symlink(target) if target.len() < 60);
+
+        put16(&mut inode, 0, self.mode);
+        put16(&mut inode, 2, self.uid as u16);
+        put32(&mut inode, 4, size as u32);
+        put16(&mut inode, 24, self.gid as u16);
+        put16(&mut inode, 26, self.links);
+        put32(&mut inode, 28, sectors as u32);
+        put32(&mut inode, 32, if fast_symlink { 0 } else { EXTENTS_FL });
+        inode[40..100].copy_from_slice(&placement.iblock);
+        put32(&mut inode, 108, (size >> 32) as u32);
+        put16(&mut inode, 116, (sectors >> 32) as u16);
+        put16(&mut inode, 120, (self.uid >> 16) as u16);
+        put16(&mut inode, 122, (self.gid >> 16) as u16);
+        put16(&mut inode, 128, EXTRA_ISIZE);
+
+        inode
+    }
+}
+
+struct Tree {
+    inodes: BTreeMap<u32, Inode>,
+    next_ino: u32,
+    /// Host (device, inode) to ext4 inode, for hardlinks.
+    links: HashMap<(u64, u64), u32>,
+}
+
+impl Tree {
+    fn new() -> Self {
+        Self {
+            inodes: BTreeMap::new(),
+            next_ino: LOST_AND_FOUND_INO + 1,
+            links: HashMap::new(),
+        }
+    }
+
+    fn build(&mut self, seed: Option<&Path>) -> anyhow::Result<()> {
+        let (mode, uid, gid) = match seed {
+            Some(seed) => {
+                let metadata = fs::metadata(seed)?;
+                (
+                    metadata.mode() as u16 & 0o7777,
+                    metadata.uid(),
+                    metadata.gid(),
+                )
+            }
+            None => (0o755, 0, 0),
+        };
+
+        let mut entries = vec![DirEntry {
+            name: b"lost+found".to_vec(),
+            ino: LOST_AND_FOUND_INO,
+            file_type: FT_DIR,
+        }];
+
+        self.inodes.insert(
+            LOST_AND_FOUND_INO,
+            Inode {
+                mode: S_IFDIR | 0o700,
+                uid: 0,
+                gid: 0,
+                links: 2,
+                data: Data::Dir(dot_entries(LOST_AND_FOUND_INO, ROOT_INO)),
+            },
+        );
+
+        if let Some(seed) = seed {
+            entries.extend(self.add_dir(seed, ROOT_INO)?);
+        }
+
+        self.insert_dir(ROOT_INO, ROOT_INO, entries, mode, uid, gid);
+        Ok(())
+    }
+
+    fn insert_dir(
+        &mut self,
+        ino: u32,
+        parent: u32,
+        entries: Vec<DirEntry>,
+        mode: u16,
+        uid: u32,
+        gid: u32,
+    ) {
+        let subdirs = entries.iter().filter(|e| e.file_type == FT_DIR).count();
+
+        let mut all = dot_entries(ino, parent);
+        all.extend(entries);
+
+        self.inodes.insert(
+            ino,
+            Inode {
+                mode: S_IFDIR | mode,
+                uid,
+                gid,
+                links: (2 + subdirs).min(u16::MAX as usize) as u16,
+                data: Data::Dir(all),
+            },
+        );
+    }
+
+    /// Adds the contents of a host directory and returns its entries.
+    fn add_dir(&mut self, src: &Path, ino: u32) -> anyhow::Result<Vec<DirEntry>> {
+        let mut dir_entries: Vec<_> = fs::read_dir(src)?.collect::<io::Result<_>>()?;
+        dir_entries.sort_by_key(|entry| entry.file_name());
+
+        let mut entries = Vec::new();
+
+        for dir_entry in dir_entries {
+            let path = dir_entry.path();
+            let name = dir_entry.file_name().as_bytes().to_vec();
+            let metadata = fs::symlink_metadata(&path)?;
+
+            if name.len() > 255 {
+                bail!("file name too long: {}", path.display());
+            }
+
+            let mode = metadata.mode() as u16 & 0o7777;
+            let (uid, gid) = (metadata.uid(), metadata.gid());
+
+            if metadata.is_dir() {
+                let child = self.alloc_ino();
+                let child_entries = self.add_dir(&path, child)?;
+                self.insert_dir(child, ino, child_entries, mode, uid, gid);
+
+                entries.push(DirEntry {
+                    name,
+                    ino: child,
+                    file_type: FT_DIR,
+                });
+            } else if metadata.is_symlink() {
+                let child = self.alloc_ino();
+                let target = fs::read_link(&path)?.as_os_str().as_bytes().to_vec();
+
+                if target.len() >= BLOCK_SIZE as usize {
+                    bail!("symlink target too long: {}", path.display());
+                }
+
+                self.inodes.insert(
+                    child,
+                    Inode {
+                        mode: S_IFLNK | 0o777,
+                        uid,
+                        gid,
+                        links: 1,
+                        data: Data::Symlink(target),
+                    },
+                );
+
+                entries.push(DirEntry {
+                    name,
+                    ino: child,
+                    file_type: FT_SYMLINK,
+                });
+            } else if metadata.is_file() {
+                let key = (metadata.dev(), metadata.ino());
+
+                let child = match self.links.get(&key) {
+                    Some(child) => {
+                        self.inodes.get_mut(child).unwrap().links += 1;
+                        *child
+                    }
+                    None => {
+                        let child = self.alloc_ino();
+                        self.inodes.insert(
+                            child,
+                            Inode {
+                                mode: S_IFREG | mode,
+                                uid,
+                                gid,
+                                links: 1,
+                                data: Data::File(path, metadata.len()),
+                            },
+                        );
+
+                        if metadata.nlink() > 1 {
+                            self.links.insert(key, child);
+                        }
+
+                        child
+                    }
+                };
+
+                entries.push(DirEntry {
+                    name,
+                    ino: child,
+                    file_type: FT_REG_FILE,
+                });
+            } else {
+                bail!("unsupported file type in data seed: {}", path.display());
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn alloc_ino(&mut self) -> u32 {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        ino
+    }
+}
+
+/// Hands out data blocks in order, skipping group metadata.
+struct Allocator<'a> {
+    layout: &'a Layout,
+    next_block: u32,
+    /// Data blocks used per group.
+    used: Vec<u32>,
+}
+
+impl<'a> Allocator<'a> {
+    fn new(layout: &'a Layout) -> Self {
+        Self {
+            layout,
+            next_block: 0,
+            used: vec![0; layout.groups as usize],
+        }
+    }
+
+    fn alloc(&mut self, mut count: u32) -> anyhow::Result<Vec<Extent>> {
+        let mut extents = Vec::new();
+
+        while count > 0 {
+            let group = self.next_block / BLOCKS_PER_GROUP;
+            if group >= self.layout.groups {
+                bail!("data seed doesn't fit into the data filesystem");
+            }
+
+            let start = self.next_block.max(self.layout.data_start(group));
+            let end = self.layout.group_start(group) + self.layout.group_len(group);
+
+            if start >= end {
+                self.next_block = self.layout.group_start(group + 1);
+                continue;
+            }
+
+            let len = count.min(end - start);
+            extents.push(Extent { start, len });
+
+            self.used[group as usize] += len;
+            self.next_block = start + len;
+            count -= len;
+        }
+
+        Ok(extents)
+    }
+
+    fn place(&mut self, inode: &Inode) -> anyhow::Result<Placement> {
+        let blocks = match &inode.data {
+            Data::Symlink(target) if target.len() < 60 => {
+                let mut iblock = [0; 60];
+                iblock[..target.len()].copy_from_slice(target);
+
+                return Ok(Placement {
+                    extents: Vec::new(),
+                    iblock,
+                    leaf: None,
+                });
+            }
+            Data::Symlink(_) => 1,
+            Data::Dir(entries) => dir_blocks(entries).len() as u32 / BLOCK_SIZE,
+            Data::File(_, size) => size
+                .div_ceil(BLOCK_SIZE as u64)
+                .try_into()
+                .map_err(|_| anyhow!("file too large"))?,
+            Data::Journal(len) => *len,
+        };
+
+        let extents = self.alloc(blocks)?;
+
+        let mut iblock = [0; 60];
+
+        if extents.len() <= 4 {
+            put_extent_header(&mut iblock, extents.len() as u16, 4, 0);
+            for (i, extent) in extents.iter().enumerate() {
+                put_extent(&mut iblock[12 + i * 12..], extent, file_block(&extents, i));
+            }
+
+            return Ok(Placement {
+                extents,
+                iblock,
+                leaf: None,
+            });
+        }
+
+        if extents.len() > MAX_LEAF_EXTENTS {
+            bail!("file too fragmented for the data filesystem");
+        }
+
+        let leaf_block = self.alloc(1)?[0].start;
+
+        let mut leaf = vec![0; BLOCK_SIZE as usize];
+        put_extent_header(&mut leaf, extents.len() as u16, MAX_LEAF_EXTENTS as u16, 0);
+        for (i, extent) in extents.iter().enumerate() {
+            put_extent(&mut leaf[12 + i * 12..], extent, file_block(&extents, i));
+        }
+
+        put_extent_header(&mut iblock, 1, 4, 1);
+        put32(&mut iblock, 12, 0); // first file block
+        put32(&mut iblock, 16, leaf_block);
+
+        Ok(Placement {
+            extents,
+            iblock,
+            leaf: Some((leaf_block, leaf)),
+        })
+    }
+}
+
+fn file_block(extents: &[Extent], i: usize) -> u32 {
+    extents[..i].iter().map(|extent| extent.len).sum()
+}
+
+fn put_extent_header(buf: &mut [u8], entries: u16, max: u16, depth: u16) {
+    put16(buf, 0, EXTENT_MAGIC);
+    put16(buf, 2, entries);
+    put16(buf, 4, max);
+    put16(buf, 6, depth);
+}
+
+fn put_extent(buf: &mut [u8], extent: &Extent, file_block: u32) {
+    put32(buf, 0, file_block);
+    put16(buf, 4, extent.len as u16);
+    put16(buf, 6, 0); // start (high)
+    put32(buf, 8, extent.start);
+}
+
+fn dot_entries(ino: u32, parent: u32) -> Vec<DirEntry> {
+    vec![
+        DirEntry {
+            name: b".".to_vec(),
+            ino,
+            file_type: FT_DIR,
+        },
+        DirEntry {
+            name: b"..".to_vec(),
+            ino: parent,
+            file_type: FT_DIR,
+        },
+    ]
+}
+
+/// Serializes directory entries into linear directory blocks.
+fn dir_blocks(entries: &[DirEntry]) -> Vec<u8> {
+    let mut buf = vec![0; BLOCK_SIZE as usize];
+    let mut pos = 0;
+    let mut last: Option<usize> = None;
+
+    for entry in entries {
+        let rec_len = (8 + entry.name.len()).next_multiple_of(4);
+        let block_end = buf.len();
+
+        if pos + rec_len > block_end {
+            // Extend the last entry to the end of the block.
+            if let Some(last) = last {
+                put16(&mut buf, last + 4, (block_end - last) as u16);
+            }
+
+            buf.resize(buf.len() + BLOCK_SIZE as usize, 0);
+            pos = block_end;
+        }
+
+        put32(&mut buf, pos, entry.ino);
+        put16(&mut buf, pos + 4, rec_len as u16);
+        buf[pos + 6] = entry.name.len() as u8;
+        buf[pos + 7] = entry.file_type;
+        buf[pos + 8..pos + 8 + entry.name.len()].copy_from_slice(&entry.name);
+
+        last = Some(pos);
+        pos += rec_len;
+    }
+
+    if let Some(last) = last {
+        let rec_len = (buf.len() - last) as u16;
+        put16(&mut buf, last + 4, rec_len);
+    }
+
+    buf
+}
+
+/// Returns a bitmap block with the first `used` bits set
+/// as well as the padding bits after `len`.
+fn bitmap(used: u32, len: u32) -> Vec<u8> {
+    let mut buf = vec![0; BLOCK_SIZE as usize];
+
+    for bit in (0..used).chain(len..BLOCK_SIZE * 8) {
+        buf[(bit / 8) as usize] |= 1 << (bit % 8);
+    }
+
+    buf
+}
+
+/// Journal size in blocks, following mke2fs' defaults.
+fn journal_blocks(blocks_count: u32) -> u32 {
+    match blocks_count {
+        0..=2047 => 0,
+        2048..=32767 => 1024,
+        32768..=262143 => 4096,
+        262144..=524287 => 8192,
+        524288..=4194303 => 16384,
+        4194304..=8388607 => 32768,
+        8388608..=16777215 => 65536,
+        16777216..=33554431 => 131072,
+        _ => 262144,
+    }
+}
+
+/// Returns an empty jbd2 superblock. Unlike the rest of ext4 the journal is big-endian.
+fn journal_superblock(len: u32, uuid: [u8; 16]) -> Vec<u8> {
+    let mut buf = vec![0; BLOCK_SIZE as usize];
+
+    buf[0..4].copy_from_slice(&JBD2_MAGIC.to_be_bytes());
+    buf[4..8].copy_from_slice(&JBD2_SUPERBLOCK_V2.to_be_bytes());
+    buf[12..16].copy_from_slice(&BLOCK_SIZE.to_be_bytes());
+    buf[16..20].copy_from_slice(&len.to_be_bytes());
+    buf[20..24].copy_from_slice(&1u32.to_be_bytes()); // first log block
+    buf[24..28].copy_from_slice(&1u32.to_be_bytes()); // first expected sequence
+    buf[48..64].copy_from_slice(&uuid);
+    buf[64..68].copy_from_slice(&1u32.to_be_bytes()); // users
+
+    buf
+}
+
+fn parse_uuid(uuid: &str) -> anyhow::Result<[u8; 16]> {
+    let hex = uuid.replace('-', "");
+
+    if hex.len() != 32 {
+        bail!("invalid UUID: {}", uuid);
+    }
+
+    let mut buf = [0; 16];
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| anyhow!("invalid UUID: {}", uuid))?;
+    }
+
+    Ok(buf)
+}
+
+/// Derives a UUID from the filesystem parameters so that identical inputs
+/// produce identical filesystems.
+fn derive_uuid(blocks_count: u32, label: &[u8]) -> [u8; 16] {
+    fn fnv1a(seed: u64, data: &[u8]) -> u64 {
+        data.iter().fold(seed, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+
+    let mut input = b"rustkrazy data".to_vec();
+    input.extend_from_slice(&blocks_count.to_le_bytes());
+    input.extend_from_slice(label);
+
+    let mut uuid = [0; 16];
+    uuid[..8].copy_from_slice(&fnv1a(0xcbf29ce484222325, &input).to_le_bytes());
+    uuid[8..].copy_from_slice(&fnv1a(0x84222325cbf29ce4, &input).to_le_bytes());
+
+    uuid[6] = (uuid[6] & 0x0f) | 0x40; // version 4
+    uuid[8] = (uuid[8] & 0x3f) | 0x80; // RFC 4122 variant
+
+    uuid
+}
+
+fn write_at<W: Write + Seek>(dev: &mut W, block: u32, buf: &[u8]) -> io::Result<()> {
+    dev.seek(SeekFrom::Start(block as u64 * BLOCK_SIZE as u64))?;
+    dev.write_all(buf)
+}
+
+fn put16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use std::process::Command;
+
+    /// Runs an e2fsprogs tool, returning `None` if it isn't installed.
+    fn e2fsprogs(program: &str, args: &[&str]) -> Option<std::process::Output> {
+        match Command::new(program).args(args).output() {
+            Ok(output) => Some(output),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => panic!("can't run {}: {}", program, e),
+        }
+    }
+
+    #[test]
+    fn round_trip() -> anyhow::Result<()> {
+        let seed = tempfile::tempdir()?;
+        let big: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+
+        fs::write(seed.path().join("hello"), b"hello\n")?;
+        fs::create_dir(seed.path().join("sub"))?;
+        fs::write(seed.path().join("sub/big"), &big)?;
+        fs::hard_link(seed.path().join("hello"), seed.path().join("sub/link"))?;
+        symlink("../hello", seed.path().join("sub/symlink"))?;
+
+        let image = tempfile::NamedTempFile::new()?;
+        let mut file = image.reopen()?;
+        file.set_len(64 << 20)?;
+        format(
+            &mut file,
+            64 << 20,
+            Some("data"),
+            None,
+            Some(seed.path()),
+            true,
+        )?;
+        drop(file);
+
+        let path = image.path().to_str().unwrap();
+
+        let fsck = match e2fsprogs("e2fsck", &["-fn", path]) {
+            Some(output) => output,
+            None => return Ok(()),
+        };
+        assert!(
+            fsck.status.success(),
+            "e2fsck: {}",
+            String::from_utf8_lossy(&fsck.stdout)
+        );
+
+        let cat = |file: &str| {
+            e2fsprogs("debugfs", &["-R", &format!("cat {}", file), path]).map(|out| out.stdout)
+        };
+
+        if let Some(contents) = cat("/sub/big") {
+            assert_eq!(contents, big);
+            assert_eq!(cat("/hello").unwrap(), b"hello\n");
+            assert_eq!(cat("/sub/link").unwrap(), b"hello\n");
+
+            let stat = e2fsprogs("debugfs", &["-R", "stat /sub/symlink", path]).unwrap();
+            assert!(String::from_utf8_lossy(&stat.stdout).contains("Fast link dest: \"../hello\""));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn labels_are_limited() {
+        let mut buf = io::Cursor::new(Vec::new());
+        assert!(format(
+            &mut buf,
+            64 << 20,
+            Some("a label that is too long"),
+            None,
+            None,
+            false
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,265 @@
+use crate::config::DataFilesystem;
+use crate::mbr::{self, LayoutEntry, Mbr};
+use crate::progress;
+use crate::squashfs::{self, Node};
+
+use serde::Serialize;
+use std::fs::File;
+use std::io::prelude::*;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Image file or device to inspect.
+    path: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    size: u64,
+    signature_valid: bool,
+    kernel_lba: u32,
+    cmdline_lba: u32,
+    partitions: Vec<LayoutEntry>,
+    boot: Section<BootReport>,
+    root_a: Section<Vec<Node>>,
+    root_b: Section<Vec<Node>>,
+    data: Section<Option<DataFilesystem>>,
+}
+
+#[derive(Debug, Serialize)]
+struct BootReport {
+    cmdline: Option<String>,
+    active_slot: Option<&'static str>,
+    files: Vec<BootFile>,
+}
+
+#[derive(Debug, Serialize)]
+struct BootFile {
+    path: String,
+    size: u64,
+}
+
+/// Part of the report that may fail without affecting the others,
+/// e.g. because a partition is damaged.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Section<T> {
+    Ok(T),
+    Error(String),
+    Missing,
+}
+
+impl<T> From<anyhow::Result<T>> for Section<T> {
+    fn from(result: anyhow::Result<T>) -> Self {
+        match result {
+            Ok(v) => Self::Ok(v),
+            Err(e) => Self::Error(e.to_string()),
+        }
+    }
+}
+
+pub fn inspect(args: Args) -> anyhow::Result<()> {
+    let mut file = File::open(&args.path)?;
+
+    let size = mbr::size(&mut file)?;
+    let mbr = Mbr::read(&mut file)?;
+
+    let section = |i: usize| mbr.partitions[i].map(|partition| partition.open(&file));
+
+    let boot = match section(0) {
+        Some(partition) => partition.and_then(|mut p| inspect_boot(&mut p)).into(),
+        None => Section::Missing,
+    };
+    let root_a = match section(1) {
+        Some(partition) => partition.and_then(|mut p| inspect_root(&mut p)).into(),
+        None => Section::Missing,
+    };
+    let root_b = match section(2) {
+        Some(partition) => partition.and_then(|mut p| inspect_root(&mut p)).into(),
+        None => Section::Missing,
+    };
+    let data = match section(3) {
+        Some(partition) => partition
+            .and_then(|mut p| Ok(DataFilesystem::detect(&mut p)?))
+            .into(),
+        None => Section::Missing,
+    };
+
+    let report = Report {
+        size,
+        signature_valid: mbr.signature_valid,
+        kernel_lba: mbr.kernel_lba,
+        cmdline_lba: mbr.cmdline_lba,
+        partitions: mbr.layout(),
+        boot,
+        root_a,
+        root_b,
+        data,
+    };
+
+    if progress::json() {
+        progress::report(&report)?;
+    } else {
+        print_report(&report);
+    }
+
+    Ok(())
+}
+
+/// Returns the root slot ("A" or "B") the kernel command line boots from.
+pub fn active_slot(cmdline: &str) -> Option<&'static str> {
+    let root = cmdline
+        .split_whitespace()
+        .find_map(|param| param.strip_prefix("root="))?;
+
+    match root.chars().last()? {
+        '2' => Some("A"),
+        '3' => Some("B"),
+        _ => None,
+    }
+}
+
+fn inspect_boot<T: fatfs::ReadWriteSeek>(partition: &mut T) -> anyhow::Result<BootReport> {
+    let fs = fatfs::FileSystem::new(partition, fatfs::FsOptions::new())?;
+    let root_dir = fs.root_dir();
+
+    let mut files = Vec::new();
+    list_fat(&root_dir, "", &mut files)?;
+
+    let cmdline = match root_dir.open_file("cmdline.txt") {
+        Ok(mut file) => {
+            let mut cmdline = String::new();
+            file.read_to_string(&mut cmdline)?;
+
+            Some(cmdline.trim().to_owned())
+        }
+        Err(_) => None,
+    };
+
+    Ok(BootReport {
+        active_slot: cmdline.as_deref().and_then(active_slot),
+        cmdline,
+        files,
+    })
+}
+
+fn list_fat<T: fatfs::ReadWriteSeek>(
+    dir: &fatfs::Dir<T>,
+    prefix: &str,
+    files: &mut Vec<BootFile>,
+) -> anyhow::Result<()> {
+    for entry in dir.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+
+        if name == "." || name == ".." {
+            continue;
+        }
+
+        let path = format!("{}/{}", prefix, name);
+
+        if entry.is_dir() {
+            list_fat(&entry.to_dir(), &path, files)?;
+        } else {
+            files.push(BootFile {
+                path,
+                size: entry.len(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn inspect_root<R: Read + Seek>(partition: &mut R) -> anyhow::Result<Vec<Node>> {
+    let squashfs = squashfs::extract(partition)?;
+    squashfs::list(squashfs.path())
+}
+
+fn print_report(report: &Report) {
+    println!("Size: {} bytes", report.size);
+    println!(
+        "MBR signature: {}",
+        if report.signature_valid {
+            "valid"
+        } else {
+            "invalid"
+        }
+    );
+    println!(
+        "Bootloader: kernel at LBA {}, cmdline at LBA {}",
+        report.kernel_lba, report.cmdline_lba
+    );
+
+    println!();
+    println!("Partitions:");
+    for p in &report.partitions {
+        println!(
+            "  {} {:<7} type 0x{:02x}  start LBA {:>9}  {:>9} sectors{}",
+            p.number,
+            p.role,
+            p.partition.kind,
+            p.partition.start_lba,
+            p.partition.sectors,
+            if p.partition.active { "  active" } else { "" }
+        );
+    }
+
+    println!();
+    match &report.boot {
+        Section::Ok(boot) => {
+            println!("Boot files:");
+            for file in &boot.files {
+                println!("  {:<32} {:>10}", file.path, file.size);
+            }
+
+            if let Some(cmdline) = &boot.cmdline {
+                println!("Kernel command line: {}", cmdline);
+            }
+            println!(
+                "Active root slot: {}",
+                boot.active_slot.unwrap_or("unknown")
+            );
+        }
+        section => print_section("Boot partition", section),
+    }
+
+    for (slot, section) in [("A", &report.root_a), ("B", &report.root_b)] {
+        println!();
+        match section {
+            Section::Ok(nodes) => {
+                println!("Root filesystem {}:", slot);
+                for node in nodes {
+                    match &node.target {
+                        Some(target) => println!(
+                            "  {:04o} {:>5}:{:<5} {:>10} {} -> {}",
+                            node.mode, node.uid, node.gid, node.size, node.path, target
+                        ),
+                        None => println!(
+                            "  {:04o} {:>5}:{:<5} {:>10} {}",
+                            node.mode, node.uid, node.gid, node.size, node.path
+                        ),
+                    }
+                }
+            }
+            section => print_section(&format!("Root filesystem {}", slot), section),
+        }
+    }
+
+    println!();
+    match &report.data {
+        Section::Ok(Some(fs)) => {
+            println!("Data filesystem: {}", format!("{:?}", fs).to_lowercase())
+        }
+        Section::Ok(None) => println!("Data filesystem: unknown"),
+        section => print_section("Data partition", section),
+    }
+}
+
+fn print_section<T>(name: &str, section: &Section<T>) {
+    match section {
+        Section::Ok(_) => {}
+        Section::Error(e) => println!("{}: {}", name, e),
+        Section::Missing => println!("{}: missing", name),
+    }
+}
@@ -0,0 +1,347 @@
+use crate::config::Config;
+use crate::mbr::LayoutEntry;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions, Permissions};
+use std::io;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the instance file format.
+/// Files without a schema field only contain the size and architecture.
+pub const SCHEMA: u32 = 2;
+
+/// Everything known about the image written to a device.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Instance {
+    #[serde(default = "schema_v1")]
+    pub schema: u32,
+    pub size: u64,
+    pub arch: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub packer_version: Option<String>,
+    /// Build time in seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(default)]
+    pub partitions: Vec<LayoutEntry>,
+    #[serde(default)]
+    pub crates: Vec<Crate>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub binaries: Vec<Binary>,
+    /// SHA-256 digests of the kernel, firmware and other boot partition files.
+    #[serde(default)]
+    pub boot_files: BTreeMap<String, String>,
+    /// The image settings (--config) so that rebuilds and pushes use them again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<Config>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update: Option<Update>,
+}
+
+/// How to reach the update service of a running device.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Update {
+    /// Base URL, e.g. https://sensor01:8443
+    pub url: String,
+    /// Shared secret sent as a bearer token.
+    pub token: String,
+}
+
+fn schema_v1() -> u32 {
+    1
+}
+
+/// A crate installed into the image.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Crate {
+    pub name: String,
+    pub version: String,
+    /// "crates.io" or the URL of the git repository.
+    pub source: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// Location of the binary in the image.
+    pub path: String,
+    pub sha256: String,
+}
+
+/// A prebuilt binary installed into the image.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Binary {
+    pub name: String,
+    /// Host path or URL the binary was taken from.
+    pub source: String,
+    /// Location of the binary in the image.
+    pub path: String,
+    pub sha256: String,
+}
+
+impl Instance {
+    pub fn new(size: u64, arch: String) -> Self {
+        Self {
+            schema: SCHEMA,
+            size,
+            arch,
+            packer_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|t| t.as_secs()),
+            partitions: Vec::new(),
+            crates: Vec::new(),
+            binaries: Vec::new(),
+            boot_files: BTreeMap::new(),
+            config: None,
+            update: None,
+        }
+    }
+
+    /// Returns the recorded crates in the format of --crates and --git
+    /// as well as the name of the init crate or binary if it is known.
+    /// The crates are pinned to the recorded version or commit unless `latest` is set.
+    pub fn crate_args(&self, latest: bool) -> (Vec<String>, Vec<String>, Option<String>) {
+        let mut crates = Vec::new();
+        let mut git = Vec::new();
+        let mut init = None;
+
+        for recorded in &self.crates {
+            if recorded.source == "crates.io" {
+                if latest {
+                    crates.push(recorded.name.clone());
+                } else {
+                    crates.push(format!("{}@{}", recorded.name, recorded.version));
+                }
+            } else {
+                match &recorded.commit {
+                    Some(commit) if !latest => git.push(format!(
+                        "{}?rev={}%{}",
+                        recorded.source, commit, recorded.name
+                    )),
+                    _ => git.push(format!("{}%{}", recorded.source, recorded.name)),
+                }
+            }
+
+            if recorded.path == "/bin/init" {
+                init = Some(recorded.name.clone());
+            }
+        }
+
+        for recorded in &self.binaries {
+            if recorded.path == "/bin/init" {
+                init = Some(recorded.name.clone());
+            }
+        }
+
+        (crates, git, init)
+    }
+
+    /// Returns the --binary arguments that install the recorded prebuilt binaries.
+    /// Downloads are pinned to the recorded checksum.
+    pub fn binary_args(&self) -> Vec<String> {
+        self.binaries
+            .iter()
+            .map(|recorded| {
+                if recorded.source.starts_with("https://") || recorded.source.starts_with("http://")
+                {
+                    format!(
+                        "{}#sha256={}%{}",
+                        recorded.source, recorded.sha256, recorded.name
+                    )
+                } else {
+                    format!("{}%{}", recorded.source, recorded.name)
+                }
+            })
+            .collect()
+    }
+
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let instance: Self = serde_json::from_reader(File::open(path)?)?;
+
+        if instance.schema > SCHEMA {
+            bail!(
+                "instance file {} has schema {} but this packer only supports up to {}",
+                path,
+                instance.schema,
+                SCHEMA
+            );
+        }
+
+        Ok(instance)
+    }
+
+    /// Writes the instance file. Only the owner may read it since it can contain the update token.
+    pub fn write(&self, path: &str) -> anyhow::Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        // The mode only applies to new files.
+        file.set_permissions(Permissions::from_mode(0o600))?;
+
+        serde_json::to_writer_pretty(&mut file, self)?;
+
+        Ok(())
+    }
+}
+
+/// Version and source of a crate as recorded by `cargo install`.
+#[derive(Clone, Debug)]
+pub struct Installed {
+    pub version: String,
+    pub source: String,
+    pub commit: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CratesV2 {
+    installs: BTreeMap<String, serde_json::Value>,
+}
+
+/// Reads the crates `cargo install` recorded in the `.crates2.json` file of `root`.
+/// The keys have the format "<name> <version> (<source>)".
+pub fn installed(root: &Path) -> anyhow::Result<BTreeMap<String, Installed>> {
+    let crates: CratesV2 = serde_json::from_reader(File::open(root.join(".crates2.json"))?)?;
+
+    let mut installed = BTreeMap::new();
+
+    for key in crates.installs.keys() {
+        let (name, version, source) = match key.split_once(' ').and_then(|(name, rest)| {
+            let (version, source) = rest.split_once(" (")?;
+            Some((name, version, source.strip_suffix(')')?))
+        }) {
+            Some(parts) => parts,
+            None => bail!("invalid cargo install record: {}", key),
+        };
+
+        let (source, commit) = match source.strip_prefix("git+") {
+            Some(git) => {
+                let (url, commit) = match git.split_once('#') {
+                    Some((url, commit)) => (url, Some(commit.to_owned())),
+                    None => (git, None),
+                };
+
+                // Drop the branch or tag, the commit identifies the source.
+                let url = url.split_once('?').map(|(url, _)| url).unwrap_or(url);
+
+                (url.to_owned(), commit)
+            }
+            None if source.contains("github.com/rust-lang/crates.io-index")
+                || source.contains("index.crates.io") =>
+            {
+                (String::from("crates.io"), None)
+            }
+            None => (source.to_owned(), None),
+        };
+
+        installed.insert(
+            name.to_owned(),
+            Installed {
+                version: version.to_owned(),
+                source,
+                commit,
+            },
+        );
+    }
+
+    Ok(installed)
+}
+
+/// Returns the lowercase hex SHA-256 digest of `data`.
+pub fn sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Returns the lowercase hex SHA-256 digest of the file at `path`.
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn write_restricts_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("instance.json");
+        let path = path.to_str().unwrap();
+
+        fs::write(path, "{}").unwrap();
+        fs::set_permissions(path, Permissions::from_mode(0o644)).unwrap();
+
+        let mut instance = Instance::new(1 << 30, String::from("x86_64"));
+        instance.update = Some(Update {
+            url: String::from("https://sensor01:8443"),
+            token: String::from("secret"),
+        });
+        instance.write(path).unwrap();
+
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let loaded = Instance::load(path).unwrap();
+        assert_eq!(loaded.update.unwrap().token, "secret");
+    }
+
+    #[test]
+    fn load_legacy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("instance.json");
+        let path = path.to_str().unwrap();
+
+        fs::write(path, r#"{ "size": 4294967296, "arch": "rpi" }"#).unwrap();
+
+        let instance = Instance::load(path).unwrap();
+        assert_eq!(instance.schema, 1);
+        assert_eq!(instance.size, 4 << 30);
+        assert_eq!(instance.arch, "rpi");
+        assert!(instance.packer_version.is_none() && instance.timestamp.is_none());
+        assert!(instance.partitions.is_empty() && instance.boot_files.is_empty());
+        assert!(instance.crates.is_empty() && instance.binaries.is_empty());
+        assert!(instance.config.is_none() && instance.update.is_none());
+        assert_eq!(instance.crate_args(false), (Vec::new(), Vec::new(), None));
+    }
+
+    #[test]
+    fn crate_args_pin_recorded_versions() {
+        let mut instance = Instance::new(1 << 30, String::from("x86_64"));
+        instance.crates = vec![
+            Crate {
+                name: String::from("rustkrazy_init"),
+                version: String::from("0.1.2"),
+                source: String::from("crates.io"),
+                commit: None,
+                path: String::from("/bin/init"),
+                sha256: String::new(),
+            },
+            Crate {
+                name: String::from("app"),
+                version: String::from("0.3.0"),
+                source: String::from("https://example.com/app.git"),
+                commit: Some(String::from("0123abcd")),
+                path: String::from("/bin/app"),
+                sha256: String::new(),
+            },
+        ];
+
+        let (crates, git, init) = instance.crate_args(false);
+        assert_eq!(crates, ["rustkrazy_init@0.1.2"]);
+        assert_eq!(git, ["https://example.com/app.git?rev=0123abcd%app"]);
+        assert_eq!(init.as_deref(), Some("rustkrazy_init"));
+
+        let (crates, git, _) = instance.crate_args(true);
+        assert_eq!(crates, ["rustkrazy_init"]);
+        assert_eq!(git, ["https://example.com/app.git%app"]);
+    }
+}
@@ -1,67 +1,237 @@
+mod cache;
+mod caps;
+mod config;
+mod data;
+mod device;
+mod elf;
+mod etc;
+mod ext4;
+mod inspect;
+mod instance;
+mod mbr;
+mod output;
+mod plan;
+mod prebuilt;
+mod progress;
+mod push;
+mod qemu;
+mod rootfs;
+mod sparse;
+mod squashfs;
+mod toolchain;
+mod verify;
+
+use config::{Config, DataFilesystem};
+use instance::Instance;
+use mbr::Mbr;
+use output::Format;
+use progress::{Level, Unit};
+use rootfs::{Contents, Entry, Rootfs};
+use squashfs::Superblock;
+
 use anyhow::bail;
 use cargo::core::compiler::{BuildConfig, CompileMode};
 use cargo::core::SourceId;
 use cargo::ops::{CompileFilter, CompileOptions};
 use cargo::util::config::Config as CargoConfig;
 use cargo::util::interning::InternedString;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use fatfs::{FatType, FormatVolumeOptions};
 use fscommon::StreamSlice;
 use reqwest::Url;
-use serde::{Deserialize, Serialize};
-use squashfs_ng::write::{
-    Source as SqsSource, SourceData as SqsSourceData, SourceFile as SqsSourceFile,
-    TreeProcessor as SqsTreeProcessor,
-};
-use std::collections::{BTreeMap, HashMap};
-use std::ffi::{OsStr, OsString};
+use squashfs_ng::write::TreeProcessor as SqsTreeProcessor;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
 use std::fs::{File, OpenOptions};
 use std::io::{self, prelude::*, SeekFrom};
-use std::os::unix::fs::PermissionsExt;
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-
-const MODE_DEVICE: u32 = 1 << 14;
+use std::str::FromStr;
+use std::time::Instant;
+use tempfile::NamedTempFile;
 
 #[allow(non_upper_case_globals)]
 const KiB: u32 = 1024;
 #[allow(non_upper_case_globals)]
 const MiB: u32 = 1024 * KiB;
 
+/// Space before the first partition plus the boot partition and both root slots.
+/// These have a fixed size, the data partition takes up the rest.
+const FIXED_SIZE: u64 = 2048 * 512 + 3 * (256 * MiB) as u64;
+
 const KERNEL_BASE: &str = "https://github.com/rustkrazy/kernel/raw/master/";
 const FIRMWARE_BASE: &str = "https://github.com/rustkrazy/firmware/raw/master/";
 
+const RPI_DTBS: [&str; 5] = [
+    "bcm2710-rpi-3-b.dtb",
+    "bcm2710-rpi-3-b-plus.dtb",
+    "bcm2710-rpi-cm3.dtb",
+    "bcm2711-rpi-4-b.dtb",
+    "bcm2710-rpi-zero-2-w.dtb",
+];
+
+const RPI_FIRMWARE: [&str; 17] = [
+    "bootcode.bin",
+    "fixup.dat",
+    "fixup4.dat",
+    "fixup4cd.dat",
+    "fixup4db.dat",
+    "fixup4x.dat",
+    "fixup_cd.dat",
+    "fixup_db.dat",
+    "fixup_x.dat",
+    "start.elf",
+    "start4.elf",
+    "start4cd.elf",
+    "start4db.elf",
+    "start4x.elf",
+    "start_cd.elf",
+    "start_db.elf",
+    "start_x.elf",
+];
+
 #[derive(Debug, Parser)]
 #[command(author = "The Rustkrazy Authors", version = "v0.1.0", about = "Generate a rustkrazy image.", long_about = None)]
+#[command(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    action: Option<Action>,
     /// Output location of a full image.
-    #[arg(short = 'o', long = "overwrite")]
-    overwrite: String,
+    #[arg(short = 'o', long = "overwrite", required = true)]
+    overwrite: Option<String>,
     /// Size of image file in bytes. Used if --overwrite is a file.
+    /// Defaults to the smallest size that fits the contents.
     #[arg(short = 'n', long = "size")]
     size: Option<u64>,
-    /// Output location of the instance file.
-    #[arg(short = 'm', long = "instance")]
-    instance: String,
+    /// Output location of the instance file. Defaults to --from-instance.
+    #[arg(
+        short = 'm',
+        long = "instance",
+        required_unless_present = "from_instance"
+    )]
+    instance: Option<String>,
+    /// Format of the image file. Devices are always written raw.
+    #[arg(short = 't', long = "format", value_enum, default_value_t = Format::Raw)]
+    format: Format,
+    /// Print the partition layout, boot files and root filesystem contents
+    /// without writing anything.
+    #[arg(long = "dry-run", conflicts_with = "json")]
+    dry_run: bool,
+    /// Overwrite devices without asking for confirmation.
+    #[arg(short = 'y', long = "yes")]
+    yes: bool,
+    /// Print progress as a stream of JSON events, one per line.
+    #[arg(long = "json", global = true)]
+    json: bool,
+    /// Only print warnings and errors.
+    #[arg(short = 'q', long = "quiet", global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Print details like individual firmware files and the output of cargo.
+    #[arg(short = 'v', long = "verbose", global = true)]
+    verbose: bool,
+    #[command(flatten)]
+    image: ImageArgs,
+}
+
+/// What to put into an image.
+#[derive(Debug, clap::Args)]
+struct ImageArgs {
+    /// Instance file of an existing device. Its size, architecture and crates
+    /// are used unless specified otherwise.
+    #[arg(short = 'u', long = "from-instance")]
+    from_instance: Option<String>,
+    /// Build the latest versions of the crates recorded in the instance file
+    /// instead of the recorded versions and commits.
+    #[arg(long = "update", requires = "from_instance")]
+    update_crates: bool,
     /// Architecture of the device running the image. Supported: x86_64 rpi.
-    #[arg(short = 'a', long = "architecture")]
-    arch: String,
-    /// Crates to install into the image.
+    #[arg(
+        short = 'a',
+        long = "architecture",
+        required_unless_present = "from_instance"
+    )]
+    arch: Option<String>,
+    /// Crates to install into the image, optionally with a version ("<name>@<version>").
     #[arg(short = 'c', long = "crates")]
     crates: Vec<String>,
     /// Crates to install from git.
     #[arg(short = 'g', long = "git")]
     git: Vec<String>,
+    /// Prebuilt static binaries to install: a host path or a URL with its
+    /// SHA-256 digest ("<url>#sha256=<hex>"), optionally followed by %<name>.
+    #[arg(long = "binary")]
+    binaries: Vec<String>,
     /// Init crate. rustkrazy_init is a reasonable default for most applications.
-    #[arg(short = 'i', long = "init")]
-    init: String,
+    #[arg(short = 'i', long = "init", required_unless_present = "from_instance")]
+    init: Option<String>,
+    /// Image settings file (JSON).
+    #[arg(short = 'f', long = "config")]
+    config: Option<String>,
+    /// Contents of root slot B: empty, same (as slot A) or the path of a squashfs image.
+    #[arg(short = 'b', long = "root-b", default_value = "empty")]
+    root_b: RootB,
+    /// Make the device grow the data partition to fill the medium on first boot.
+    #[arg(long = "grow-on-first-boot")]
+    grow_on_first_boot: bool,
+    #[command(flatten)]
+    cache: cache::CacheArgs,
+}
+
+/// Operations on existing images. Without one an image is built.
+#[derive(Debug, Subcommand)]
+enum Action {
+    /// Show the partitions and contents of an image file or device.
+    Inspect(inspect::Args),
+    /// Check that an image file or device is consistent and bootable.
+    Verify(verify::Args),
+    /// Build a new root filesystem and boot files and upload them to a running device.
+    Push(push::Args),
+    /// Build an image file and boot it in QEMU.
+    Run(qemu::RunArgs),
+    /// Boot an image file in QEMU without a display and wait for it to come up.
+    TestBoot(qemu::TestBootArgs),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct Instance {
-    size: u64,
+/// The contents of an image, independent of where it is written to.
+#[derive(Clone, Debug)]
+struct Image {
     arch: String,
+    crates: Vec<String>,
+    git: Vec<String>,
+    binaries: Vec<prebuilt::Binary>,
+    init: String,
+    config: Config,
+    root_b: RootB,
+    grow_data: bool,
+    /// Update service settings carried over from the previous instance file.
+    update: Option<instance::Update>,
+    /// Directory for compiled crates kept between runs. `None` compiles from scratch.
+    cache: Option<PathBuf>,
+    /// Parameters appended to cmdline.txt, e.g. the serial console of a virtual machine.
+    kernel_args: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+enum RootB {
+    /// Only the directory skeleton.
+    Empty,
+    /// A copy of root slot A.
+    Same,
+    /// An existing squashfs image, e.g. a recovery system.
+    Image(PathBuf),
+}
+
+impl FromStr for RootB {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "empty" => Self::Empty,
+            "same" => Self::Same,
+            _ => Self::Image(PathBuf::from(s)),
+        })
+    }
 }
 
 #[cfg(target_os = "linux")]
@@ -87,7 +257,11 @@ fn device_size(file: &File, path: String) -> anyhow::Result<u64> {
     Ok(dev_size)
 }
 
-fn write_mbr_partition_table(file: &mut File, dev_size: u64) -> anyhow::Result<()> {
+fn write_mbr_partition_table<W: Write>(
+    file: &mut W,
+    dev_size: u64,
+    data_fs: DataFilesystem,
+) -> anyhow::Result<()> {
     const INACTIVE: &[u8] = &[0x00];
     const ACTIVE: &[u8] = &[0x80];
     const INVALID_CHS: &[u8] = &[0xFF, 0xFF, 0xFE]; // Causes sector values to be used
@@ -123,16 +297,35 @@ fn write_mbr_partition_table(file: &mut File, dev_size: u64) -> anyhow::Result<(
     file.write_all(&(256 * MiB / 512).to_le_bytes())?;
 
     // Partition 4: data
-    file.write_all(INACTIVE)?;
-    file.write_all(INVALID_CHS)?;
-    file.write_all(LINUX)?;
-    file.write_all(INVALID_CHS)?;
-    file.write_all(&(2048 + 3 * (256 * MiB / 512)).to_le_bytes())?;
-    file.write_all(&(dev_size as u32 / 512 - 2048 - 3 * (256 * MiB / 512)).to_le_bytes())?;
+    match data_fs.partition_type() {
+        Some(data_type) => {
+            file.write_all(INACTIVE)?;
+            file.write_all(INVALID_CHS)?;
+            file.write_all(&[data_type])?;
+            file.write_all(INVALID_CHS)?;
+            file.write_all(&(2048 + 3 * (256 * MiB / 512)).to_le_bytes())?;
+            file.write_all(&(((dev_size - FIXED_SIZE) / 512) as u32).to_le_bytes())?;
+        }
+        None => file.write_all(&[0; 16])?, // Unused entry
+    }
 
     file.write_all(SIGNATURE)?;
 
-    println!("Partition table written successfully");
+    Ok(())
+}
+
+/// Checks that the partitions fit into `dev_size` bytes.
+fn check_size(dev_size: u64, image: &Image) -> anyhow::Result<()> {
+    let min_data = image.config.data.filesystem.min_size();
+
+    if dev_size / 512 * 512 < FIXED_SIZE + min_data {
+        bail!(
+            "destination is too small ({} bytes, need at least {} bytes)",
+            dev_size,
+            FIXED_SIZE + min_data
+        );
+    }
+
     Ok(())
 }
 
@@ -140,24 +333,41 @@ fn partition(
     file: &mut File,
     dev_size: u64,
     instance: String,
-    arch: String,
-    crates: Vec<String>,
-    git: Vec<String>,
-    init: String,
+    image: Image,
+    sparse: bool,
 ) -> anyhow::Result<()> {
     const ROOT_A_START: u64 = (2048 * 512 + 256 * MiB) as u64;
     let root_a_end = ROOT_A_START + (256 * MiB) as u64;
     let root_b_end = root_a_end + (256 * MiB) as u64;
-    let data_end = root_b_end + (dev_size / 512 - 2048 - 3 * (256 * MiB / 512) as u64);
+    let data_end = dev_size / 512 * 512;
+
+    check_size(dev_size, &image)?;
+
+    if sparse {
+        // Discard the old contents so that the image only allocates blocks that are written.
+        file.set_len(0)?;
+        file.set_len(dev_size)?;
+    }
+
+    write_mbr_partition_table(file, dev_size, image.config.data.filesystem)?;
+    progress::info("Partition table written successfully");
 
-    write_mbr_partition_table(file, dev_size)?;
+    let mut info = Instance::new(dev_size, image.arch.clone());
+    info.partitions = Mbr::read(file)?.layout();
+    info.config = Some(image.config.clone());
+    info.update = image.update.clone();
 
     let mut boot_partition = StreamSlice::new(file.try_clone()?, 2048 * 512, ROOT_A_START - 1)?;
     let mut root_partition_a = StreamSlice::new(file.try_clone()?, ROOT_A_START, root_a_end - 1)?;
     let mut root_partition_b = StreamSlice::new(file.try_clone()?, root_a_end, root_b_end - 1)?;
-    let mut data_partition = StreamSlice::new(file.try_clone()?, root_b_end, data_end - 1)?;
 
-    let buf = write_boot(&mut boot_partition, &arch)?;
+    let buf = write_boot(
+        &mut boot_partition,
+        &image.arch,
+        image.kernel_args.as_deref(),
+        image.grow_data,
+        sparse,
+    )?;
     write_mbr(
         file,
         &mut boot_partition,
@@ -165,11 +375,36 @@ fn partition(
         &buf["cmdline.txt"],
     )?;
 
-    write_root(&mut root_partition_a, &arch, &crates, &git, &init)?;
-    write_empty_root(&mut root_partition_b)?;
-    format_ext4(&mut data_partition)?;
+    info.boot_files = buf
+        .iter()
+        .map(|(name, contents)| (name.clone(), instance::sha256(contents)))
+        .collect();
+
+    let (mut root_a, crates, binaries) = build_root(&image)?;
+    info.crates = crates;
+    info.binaries = binaries;
+
+    write_squashfs(&mut root_partition_a, root_a.as_file_mut(), "A", sparse)?;
+
+    match &image.root_b {
+        RootB::Empty => write_squashfs(
+            &mut root_partition_b,
+            build_empty_root()?.as_file_mut(),
+            "B",
+            sparse,
+        )?,
+        RootB::Same => write_squashfs(&mut root_partition_b, root_a.as_file_mut(), "B", sparse)?,
+        RootB::Image(path) => {
+            write_squashfs(&mut root_partition_b, &mut File::open(path)?, "B", sparse)?
+        }
+    }
+
+    if data_end > root_b_end {
+        let mut data_partition = StreamSlice::new(file.try_clone()?, root_b_end, data_end - 1)?;
+        data::format_data(&mut data_partition, &image.config.data, sparse)?;
+    }
 
-    write_instance(&instance, dev_size, arch)?;
+    info.write(&instance)?;
 
     Ok(())
 }
@@ -177,16 +412,24 @@ fn partition(
 fn partition_device(
     file: &mut File,
     overwrite: String,
+    expected_size: Option<u64>,
     instance: String,
-    arch: String,
-    crates: Vec<String>,
-    git: Vec<String>,
-    init: String,
+    image: Image,
 ) -> anyhow::Result<()> {
     let dev_size = device_size(file, overwrite)?;
-    println!("Destination holds {} bytes", dev_size);
+    progress::info(format!("Destination holds {} bytes", dev_size));
+
+    if let Some(expected_size) = expected_size {
+        if dev_size != expected_size {
+            bail!(
+                "destination holds {} bytes but the instance file says {}",
+                dev_size,
+                expected_size
+            );
+        }
+    }
 
-    partition(file, dev_size, instance, arch, crates, git, init)?;
+    partition(file, dev_size, instance, image, false)?;
 
     Ok(())
 }
@@ -194,6 +437,9 @@ fn partition_device(
 fn write_boot(
     partition: &mut StreamSlice<File>,
     arch: &str,
+    kernel_args: Option<&str>,
+    grow_data: bool,
+    sparse: bool,
 ) -> anyhow::Result<BTreeMap<String, Vec<u8>>> {
     match arch {
         "x86_64" => {}
@@ -201,16 +447,21 @@ fn write_boot(
         _ => bail!("invalid architecture (supported: x86_64 rpi)"),
     }
 
-    println!("Zeroing boot partition...");
+    let phase = progress::phase("boot", "Creating boot filesystem...");
 
     partition.seek(SeekFrom::End(0))?;
     let partition_len = partition.stream_position()?;
 
-    let mut zero = Vec::new();
-    zero.resize(partition_len as usize, 0);
-
     partition.rewind()?;
-    partition.write_all(&zero)?;
+    if sparse {
+        sparse::zero(partition, partition_len, sparse)?;
+    } else {
+        let mut zeros = progress::bar("Zeroing boot partition", partition_len, Unit::Bytes)
+            .wrap(io::repeat(0).take(partition_len));
+
+        io::copy(&mut zeros, partition)?;
+        zeros.finish();
+    }
     partition.rewind()?;
 
     let format_opts = FormatVolumeOptions::new().fat_type(FatType::Fat32);
@@ -220,7 +471,7 @@ fn write_boot(
     let fs = fatfs::FileSystem::new(partition, fatfs::FsOptions::new())?;
     let root_dir = fs.root_dir();
 
-    println!("Installing kernel...");
+    progress::info("Installing kernel...");
 
     let mut buf = BTreeMap::new();
 
@@ -233,72 +484,76 @@ fn write_boot(
     for (dst, src) in copy {
         let mut file = root_dir.create_file(dst)?;
 
-        let mut resp = reqwest::blocking::get(KERNEL_BASE.to_owned() + &src)?.error_for_status()?;
+        let mut contents = download(&(KERNEL_BASE.to_owned() + &src), dst)?;
+        if let (Some(kernel_args), "cmdline.txt") = (kernel_args, dst) {
+            contents = append_cmdline(&contents, kernel_args);
+        }
 
-        buf.insert(dst.to_owned(), Vec::new());
-        resp.copy_to(buf.get_mut(dst).unwrap())?;
-        io::copy(&mut buf.get(dst).unwrap().as_slice(), &mut file)?;
+        file.write_all(&contents)?;
+
+        buf.insert(dst.to_owned(), contents);
+    }
+
+    // The device deletes the marker after growing the data partition.
+    if grow_data {
+        root_dir.create_file("grow-data")?;
     }
 
     // We don't need the firmware to boot on other supported architectures.
     if arch == "rpi" {
-        println!("Installing RPi dtbs...");
-
-        let dtbcopy = [
-            "bcm2710-rpi-3-b.dtb",
-            "bcm2710-rpi-3-b-plus.dtb",
-            "bcm2710-rpi-cm3.dtb",
-            "bcm2711-rpi-4-b.dtb",
-            "bcm2710-rpi-zero-2-w.dtb",
-        ];
+        progress::info("Installing RPi dtbs...");
 
-        for dtb in dtbcopy {
-            println!("Installing RPi dtb: {}", dtb);
+        for dtb in RPI_DTBS {
+            progress::detail(format!("Installing RPi dtb: {}", dtb));
 
             let mut file = root_dir.create_file(dtb)?;
 
-            let mut resp =
-                reqwest::blocking::get(KERNEL_BASE.to_owned() + dtb)?.error_for_status()?;
-            resp.copy_to(&mut file)?;
+            let contents = download(&(KERNEL_BASE.to_owned() + dtb), dtb)?;
+            file.write_all(&contents)?;
+
+            buf.insert(dtb.to_owned(), contents);
         }
 
-        println!("Installing RPi firmware...");
-
-        let fwcopy = [
-            "bootcode.bin",
-            "fixup.dat",
-            "fixup4.dat",
-            "fixup4cd.dat",
-            "fixup4db.dat",
-            "fixup4x.dat",
-            "fixup_cd.dat",
-            "fixup_db.dat",
-            "fixup_x.dat",
-            "start.elf",
-            "start4.elf",
-            "start4cd.elf",
-            "start4db.elf",
-            "start4x.elf",
-            "start_cd.elf",
-            "start_db.elf",
-            "start_x.elf",
-        ];
-
-        for fw in fwcopy {
-            println!("Installing RPi firmware: {}", fw);
+        progress::info("Installing RPi firmware...");
+
+        for fw in RPI_FIRMWARE {
+            progress::detail(format!("Installing RPi firmware: {}", fw));
 
             let mut file = root_dir.create_file(fw)?;
 
-            let mut resp =
-                reqwest::blocking::get(FIRMWARE_BASE.to_owned() + fw)?.error_for_status()?;
-            resp.copy_to(&mut file)?;
+            let contents = download(&(FIRMWARE_BASE.to_owned() + fw), fw)?;
+            file.write_all(&contents)?;
+
+            buf.insert(fw.to_owned(), contents);
         }
     }
 
-    println!("Boot filesystem created successfully");
+    progress::info("Boot filesystem created successfully");
+    phase.end();
+
     Ok(buf)
 }
 
+/// Appends parameters to the contents of cmdline.txt, keeping it a single line.
+fn append_cmdline(cmdline: &[u8], args: &str) -> Vec<u8> {
+    let cmdline = String::from_utf8_lossy(cmdline);
+    format!("{} {}\n", cmdline.trim_end(), args).into_bytes()
+}
+
+/// Downloads a file, showing the progress.
+fn download(url: &str, name: &str) -> anyhow::Result<Vec<u8>> {
+    let resp = reqwest::blocking::get(url)?.error_for_status()?;
+    let len = resp.content_length().unwrap_or(0);
+
+    let mut resp = progress::bar(format!("Downloading {}", name), len, Unit::Bytes).wrap(resp);
+
+    let mut contents = Vec::new();
+    resp.read_to_end(&mut contents)?;
+    resp.finish();
+
+    Ok(contents)
+}
+
 fn write_mbr(
     file: &mut File,
     boot_partition: &mut StreamSlice<File>,
@@ -339,20 +594,26 @@ fn write_mbr(
     file.write_all(&bootloader_buf[..432])?;
     file.write_all(&bootloader_params)?;
 
-    println!("MBR written successfully");
-    println!("MBR summary:");
-    println!("  LBA: vmlinuz={}, cmdline.txt={}", kernel_lba, cmdline_lba);
+    progress::info("MBR written successfully");
+    progress::detail("MBR summary:");
+    progress::detail(format!(
+        "  LBA: vmlinuz={}, cmdline.txt={}",
+        kernel_lba, cmdline_lba
+    ));
 
     Ok(())
 }
 
-fn write_root(
-    partition: &mut StreamSlice<File>,
-    arch: &str,
-    crates: &Vec<String>,
-    git: &Vec<String>,
-    init: &str,
-) -> anyhow::Result<()> {
+/// Builds the root filesystem and returns it along with the installed crates
+/// and prebuilt binaries.
+fn build_root(
+    image: &Image,
+) -> anyhow::Result<(NamedTempFile, Vec<instance::Crate>, Vec<instance::Binary>)> {
+    let arch = image.arch.as_str();
+    let crates = &image.crates;
+    let git = &image.git;
+    let init = image.init.as_str();
+
     let target = match arch {
         "x86_64" => "x86_64",
         "rpi" => "aarch64",
@@ -361,526 +622,608 @@ fn write_root(
 
     let target_triple = format!("{}-unknown-linux-musl", target);
 
-    println!("Installing crates: {:?}", crates);
-    println!("Installing git: {:?}", git);
+    progress::info(format!("Installing crates: {:?}", crates));
+    progress::info(format!("Installing git: {:?}", git));
 
-    let tmp_dir = tempfile::tempdir()?;
+    // Prebuilt binaries are checked first so that a bad one doesn't waste a build.
+    let downloads = tempfile::tempdir()?;
+    let mut prebuilt = Vec::new();
 
-    let mut cargo_opts = CargoConfig::default()?;
-    let mut compile_opts = CompileOptions::new(&CargoConfig::default()?, CompileMode::Build)?;
+    for binary in &image.binaries {
+        let (source, sha256) = binary.fetch(arch, downloads.path())?;
+        prebuilt.push((binary, source, sha256));
+    }
 
-    cargo_opts.configure(0, false, None, false, false, false, &None, &[], &[])?;
-    compile_opts.build_config = BuildConfig::new(
-        &CargoConfig::default()?,
-        None,
-        false,
-        &[target_triple],
-        CompileMode::Build,
+    let toolchain = match image.config.toolchains.get(arch) {
+        Some(toolchain) => toolchain.clone(),
+        None => toolchain::default_for(arch),
+    };
+    // The cache is keyed on the configuration rather than the resolved settings,
+    // which contain paths inside the cache.
+    let builds = cache::Builds::open(
+        image.cache.as_ref(),
+        &target_triple,
+        &serde_json::to_string(&toolchain)?,
     )?;
-    compile_opts.build_config.requested_profile = InternedString::new("release");
 
-    if arch == "rpi" {
-        let rustc_args = vec![
-            String::from("-C"),
-            String::from("linker=aarch64-linux-gnu-ld"),
-        ];
+    let resolved = toolchain::resolve(&toolchain, target, &target_triple, &builds.toolchain_dir())?;
 
-        compile_opts.target_rustc_args = Some(rustc_args);
+    for (key, value) in &resolved.env {
+        progress::detail(format!("{}={}", key, value));
     }
 
-    for crate_name in crates {
-        compile_opts.filter = CompileFilter::single_bin(crate_name.to_owned());
+    if let Some(cache) = &image.cache {
+        progress::detail(format!("Build cache: {}", cache.display()));
+    }
+
+    // All builds share a target directory so that common dependencies are only compiled once.
+    let settings = BuildSettings {
+        triple: target_triple,
+        rustc_args: resolved.rustc_args,
+        env: resolved.env,
+        target_dir: builds.target_dir(),
+    };
+
+    let mut sources = Vec::new();
 
-        cargo::ops::install(
-            &cargo_opts,
-            Some(tmp_dir.path().to_str().unwrap()), // root (output dir)
-            vec![(crate_name.to_string(), None)],
-            SourceId::crates_io(&CargoConfig::default()?)?,
-            false, // from_cwd
-            &compile_opts,
-            false, // force
-            true,  // no_track
-        )?;
+    for spec in crates {
+        let (name, version) = crate_spec(spec);
+        let root = builds.root(name, "crates.io")?;
+        sources.push((
+            name.to_owned(),
+            CrateSource::CratesIo(version.map(str::to_owned)),
+            root,
+        ));
     }
 
     for location in git {
-        let mut split = location.split('%');
+        let (url, pkg) = git_crate(location)?;
 
-        let url = Url::parse(split.next().unwrap())?;
+        // Cargo's install record keeps track of the commit.
+        let mut repo = url.clone();
+        repo.set_query(None);
+        let root = builds.root(&pkg, repo.as_str())?;
 
-        let pkg = split.next().unwrap_or(
-            url.path_segments()
-                .unwrap()
-                .next_back()
-                .unwrap()
-                .trim_end_matches(".git"),
-        );
+        sources.push((pkg, CrateSource::Git(url), root));
+    }
 
-        compile_opts.filter = CompileFilter::single_bin(pkg.to_owned());
+    let phase = progress::phase("build", "Building crates...");
+    let mut bar = progress::bar("Building crates", sources.len() as u64, Unit::Items);
 
-        cargo::ops::install(
-            &cargo_opts,
-            Some(tmp_dir.path().to_str().unwrap()), // root (output dir)
-            vec![(pkg.to_string(), None)],
-            SourceId::from_url(&("git+".to_owned() + url.as_str()))?,
-            false, // from_cwd
-            &compile_opts,
-            false, // force
-            true,  // no_track
-        )?;
+    // Each crate gets its own cargo session and install root because cargo locks
+    // the install root for the whole installation. They are built one after another
+    // since cargo also locks the shared target directory and uses all CPUs for a single build.
+    for (pkg, source, root) in &sources {
+        bar.set_item(pkg.as_str());
+
+        let start = Instant::now();
+        let result = install_crate(pkg, source, root, &settings);
+
+        progress::crate_built(pkg, start.elapsed(), &result);
+        result?;
+
+        bar.inc(1);
     }
 
-    let mut tmp_file = tempfile::NamedTempFile::new()?;
-    io::copy(partition, &mut tmp_file)?;
+    bar.finish();
+    phase.end();
 
-    let tree = SqsTreeProcessor::new(tmp_file.path())?;
+    let mut rootfs = Rootfs::new();
+    let mut installed = Vec::new();
 
-    let mut crate_inodes = Vec::new();
+    for (pkg, _, root) in &sources {
+        let path = Path::new("/bin").join(if pkg == init { "init" } else { pkg });
+        let binary = root.join("bin").join(pkg);
 
-    for pkg in crates {
-        let crate_path = tmp_dir.path().join("bin/".to_owned() + pkg);
-        let crate_file = File::open(crate_path)?;
+        let record = match instance::installed(root)?.remove(pkg) {
+            Some(record) => record,
+            None => bail!("cargo didn't record the installation of {}", pkg),
+        };
 
-        crate_inodes.push(tree.add(SqsSourceFile {
-            path: Path::new("/bin").join(if pkg == init { "init" } else { pkg }),
-            content: SqsSource {
-                data: SqsSourceData::File(Box::new(crate_file)),
-                uid: 0,
-                gid: 0,
-                mode: 0o755,
-                modified: 0,
-                xattrs: HashMap::new(),
-                flags: 0,
-            },
-        })?);
+        installed.push(instance::Crate {
+            name: pkg.to_owned(),
+            version: record.version,
+            source: record.source,
+            commit: record.commit,
+            path: path.to_string_lossy().into_owned(),
+            sha256: instance::sha256_file(&binary)?,
+        });
+
+        rootfs.insert(path, Entry::executable(Contents::Path(binary)))?;
     }
 
-    for location in git {
-        let mut split = location.split('%');
+    let mut binaries = Vec::new();
+
+    for (binary, source, sha256) in prebuilt {
+        let name = binary.name.as_str();
+        let path = Path::new("/bin").join(if name == init { "init" } else { name });
+
+        binaries.push(instance::Binary {
+            name: name.to_owned(),
+            source: binary.location(),
+            path: path.to_string_lossy().into_owned(),
+            sha256,
+        });
+
+        rootfs.insert(path, Entry::executable(Contents::Path(source)))?;
+    }
+
+    add_config(&mut rootfs, image)?;
+
+    let mut tmp_file = NamedTempFile::new()?;
+
+    match &image.config.compression {
+        Some(compression) => {
+            progress::info(format!("Compressing root filesystem: {:?}", compression));
+            squashfs::create(&rootfs, tmp_file.path(), compression)?;
+        }
+        None => {
+            let tree = SqsTreeProcessor::new(tmp_file.path())?;
+            rootfs.write(&tree)?;
+            tree.finish()?;
+        }
+    }
+
+    tmp_file.rewind()?;
+    let superblock = Superblock::read(&mut tmp_file)?;
+
+    squashfs::report_ratio(rootfs.data_size()?, superblock.bytes_used);
+
+    Ok((tmp_file, installed, binaries))
+}
+
+/// Where a crate is installed from.
+#[derive(Clone, Debug)]
+enum CrateSource {
+    /// With an optional version requirement.
+    CratesIo(Option<String>),
+    /// A branch, tag or commit can be selected with the query of the URL, e.g. `?rev=<commit>`.
+    Git(Url),
+}
+
+/// Compiler settings shared by all crates of an image.
+#[derive(Clone, Debug)]
+struct BuildSettings {
+    triple: String,
+    rustc_args: Vec<String>,
+    /// Environment variables for build scripts.
+    env: Vec<(String, String)>,
+    target_dir: PathBuf,
+}
+
+/// Builds a crate in its own cargo session and installs its binary into `root`/bin.
+fn install_crate(
+    pkg: &str,
+    source: &CrateSource,
+    root: &Path,
+    settings: &BuildSettings,
+) -> anyhow::Result<()> {
+    let mut cargo_opts = CargoConfig::default()?;
+    let mut compile_opts = CompileOptions::new(&CargoConfig::default()?, CompileMode::Build)?;
+
+    // The variables go into cargo's [env] table like `--config` arguments would
+    // so that the environment of the packer stays untouched.
+    let mut cli_config = Vec::new();
+    for (key, value) in &settings.env {
+        cli_config.push(format!(
+            "env.{}.value={}",
+            key,
+            serde_json::to_string(value)?
+        ));
+        cli_config.push(format!("env.{}.force=true", key));
+    }
+
+    // Cargo's own output would garble progress bars and JSON events.
+    let verbose = progress::level() == Level::Verbose;
+    cargo_opts.configure(
+        verbose as u32,
+        !verbose,
+        None,
+        false,
+        false,
+        false,
+        &Some(settings.target_dir.clone()),
+        &[],
+        &cli_config,
+    )?;
+    compile_opts.build_config = BuildConfig::new(
+        &CargoConfig::default()?,
+        None,
+        false,
+        std::slice::from_ref(&settings.triple),
+        CompileMode::Build,
+    )?;
+    compile_opts.build_config.requested_profile = InternedString::new("release");
+    if !settings.rustc_args.is_empty() {
+        compile_opts.target_rustc_args = Some(settings.rustc_args.clone());
+    }
+    compile_opts.filter = CompileFilter::single_bin(pkg.to_owned());
+
+    let (source_id, version) = match source {
+        CrateSource::CratesIo(version) => (SourceId::crates_io(&cargo_opts)?, version.as_deref()),
+        CrateSource::Git(url) => (
+            SourceId::from_url(&("git+".to_owned() + url.as_str()))?,
+            None,
+        ),
+    };
+
+    // Like `cargo install <name>@<version>` a plain version selects exactly that release.
+    let version = version
+        .map(|version| {
+            if version.starts_with(|c: char| c.is_ascii_digit()) {
+                format!("={}", version)
+            } else {
+                version.to_owned()
+            }
+        })
+        .map(|version| version.parse())
+        .transpose()?;
+
+    cargo::ops::install(
+        &cargo_opts,
+        Some(root.to_str().unwrap()), // root (output dir)
+        vec![(pkg.to_owned(), version)],
+        source_id,
+        false, // from_cwd
+        &compile_opts,
+        false, // force
+        false, // no_track
+    )
+}
+
+/// Splits a --crates argument ("<name>[@<version>]") into the name and version requirement.
+fn crate_spec(spec: &str) -> (&str, Option<&str>) {
+    match spec.split_once('@') {
+        Some((name, version)) => (name, Some(version)),
+        None => (spec, None),
+    }
+}
+
+/// Returns the URL and package name of a --git crate ("<url>[%<package>]").
+/// The package name defaults to the last path segment of the URL.
+fn git_crate(location: &str) -> anyhow::Result<(Url, String)> {
+    let mut split = location.split('%');
 
-        let url = Url::parse(split.next().unwrap())?;
+    let url = Url::parse(split.next().unwrap())?;
 
-        let pkg = split.next().unwrap_or(
+    let pkg = split
+        .next()
+        .unwrap_or(
             url.path_segments()
                 .unwrap()
                 .next_back()
                 .unwrap()
                 .trim_end_matches(".git"),
-        );
+        )
+        .to_owned();
 
-        let crate_path = tmp_dir.path().join("bin/".to_owned() + pkg);
-        let crate_file = File::open(crate_path)?;
-
-        crate_inodes.push(tree.add(SqsSourceFile {
-            path: Path::new("/bin").join(if pkg == init { "init" } else { pkg }),
-            content: SqsSource {
-                data: SqsSourceData::File(Box::new(crate_file)),
-                uid: 0,
-                gid: 0,
-                mode: 0o755,
-                modified: 0,
-                xattrs: HashMap::new(),
-                flags: 0,
-            },
-        })?);
-    }
-
-    let init2 = String::from(init);
-
-    let bin_inode = tree.add(SqsSourceFile {
-        path: PathBuf::from("/bin"),
-        content: SqsSource {
-            data: SqsSourceData::Dir(Box::new(
-                crates
-                    .clone()
-                    .into_iter()
-                    .map(move |pkg| {
-                        if pkg == init2 {
-                            String::from("init")
-                        } else {
-                            pkg
-                        }
-                    })
-                    .map(OsString::from)
-                    .zip(crate_inodes.into_iter()),
-            )),
-            uid: 0,
-            gid: 0,
-            mode: 0o755,
-            modified: 0,
-            xattrs: HashMap::new(),
-            flags: 0,
-        },
-    })?;
-
-    let dev_inode = tree.add(SqsSourceFile {
-        path: PathBuf::from("/dev"),
-        content: SqsSource {
-            data: SqsSourceData::Dir(Box::new(Vec::new().into_iter())),
-            uid: 0,
-            gid: 0,
-            mode: 0o755,
-            modified: 0,
-            xattrs: HashMap::new(),
-            flags: 0,
-        },
-    })?;
-
-    let boot_inode = tree.add(SqsSourceFile {
-        path: PathBuf::from("/boot"),
-        content: SqsSource {
-            data: SqsSourceData::Dir(Box::new(Vec::new().into_iter())),
-            uid: 0,
-            gid: 0,
-            mode: 0o755,
-            modified: 0,
-            xattrs: HashMap::new(),
-            flags: 0,
-        },
-    })?;
-
-    let data_inode = tree.add(SqsSourceFile {
-        path: PathBuf::from("/data"),
-        content: SqsSource {
-            data: SqsSourceData::Dir(Box::new(Vec::new().into_iter())),
-            uid: 0,
-            gid: 0,
-            mode: 0o755,
-            modified: 0,
-            xattrs: HashMap::new(),
-            flags: 0,
-        },
-    })?;
-
-    let proc_inode = tree.add(SqsSourceFile {
-        path: PathBuf::from("/proc"),
-        content: SqsSource {
-            data: SqsSourceData::Dir(Box::new(Vec::new().into_iter())),
-            uid: 0,
-            gid: 0,
-            mode: 0o755,
-            modified: 0,
-            xattrs: HashMap::new(),
-            flags: 0,
-        },
-    })?;
-
-    let tmp_inode = tree.add(SqsSourceFile {
-        path: PathBuf::from("/tmp"),
-        content: SqsSource {
-            data: SqsSourceData::Dir(Box::new(Vec::new().into_iter())),
-            uid: 0,
-            gid: 0,
-            mode: 0o755,
-            modified: 0,
-            xattrs: HashMap::new(),
-            flags: 0,
-        },
-    })?;
-
-    let run_inode = tree.add(SqsSourceFile {
-        path: PathBuf::from("/run"),
-        content: SqsSource {
-            data: SqsSourceData::Dir(Box::new(Vec::new().into_iter())),
-            uid: 0,
-            gid: 0,
-            mode: 0o755,
-            modified: 0,
-            xattrs: HashMap::new(),
-            flags: 0,
-        },
-    })?;
-
-    tree.add(SqsSourceFile {
-        path: PathBuf::from("/"),
-        content: SqsSource {
-            data: SqsSourceData::Dir(Box::new(
-                vec![
-                    (OsString::from("bin"), bin_inode),
-                    (OsString::from("dev"), dev_inode),
-                    (OsString::from("boot"), boot_inode),
-                    (OsString::from("data"), data_inode),
-                    (OsString::from("proc"), proc_inode),
-                    (OsString::from("tmp"), tmp_inode),
-                    (OsString::from("run"), run_inode),
-                ]
-                .into_iter(),
-            )),
-            uid: 0,
-            gid: 0,
-            mode: 0o755,
-            modified: 0,
-            xattrs: HashMap::new(),
-            flags: 0,
-        },
-    })?;
+    Ok((url, pkg))
+}
 
-    tree.finish()?;
+/// Adds everything except the crates to the root filesystem.
+fn add_config(rootfs: &mut Rootfs, image: &Image) -> anyhow::Result<()> {
+    etc::add_etc(rootfs, &image.config, &image.arch)?;
 
-    tmp_file.rewind()?;
-    partition.rewind()?;
-    io::copy(&mut tmp_file, partition)?;
+    if let Some(overlay) = &image.config.overlay {
+        rootfs.add_overlay(overlay)?;
+    }
+
+    rootfs.add_special(&image.config)?;
+    rootfs.add_files(&image.config)?;
 
-    println!("Root filesystem A created successfully");
     Ok(())
 }
 
-fn write_empty_root(partition: &mut StreamSlice<File>) -> anyhow::Result<()> {
-    let mut tmp_file = tempfile::NamedTempFile::new()?;
-    io::copy(partition, &mut tmp_file)?;
+/// Creates a squashfs root filesystem containing only the directory skeleton.
+fn build_empty_root() -> anyhow::Result<NamedTempFile> {
+    let tmp_file = NamedTempFile::new()?;
 
     let tree = SqsTreeProcessor::new(tmp_file.path())?;
-
-    let bin_inode = tree.add(SqsSourceFile {
-        path: PathBuf::from("/bin"),
-        content: SqsSource {
-            data: SqsSourceData::Dir(Box::new(Vec::new().into_iter())),
-            uid: 0,
-            gid: 0,
-            mode: 0o755,
-            modified: 0,
-            xattrs: HashMap::new(),
-            flags: 0,
-        },
-    })?;
-
-    let dev_inode = tree.add(SqsSourceFile {
-        path: PathBuf::from("/dev"),
-        content: SqsSource {
-            data: SqsSourceData::Dir(Box::new(Vec::new().into_iter())),
-            uid: 0,
-            gid: 0,
-            mode: 0o755,
-            modified: 0,
-            xattrs: HashMap::new(),
-            flags: 0,
-        },
-    })?;
-
-    let boot_inode = tree.add(SqsSourceFile {
-        path: PathBuf::from("/boot"),
-        content: SqsSource {
-            data: SqsSourceData::Dir(Box::new(Vec::new().into_iter())),
-            uid: 0,
-            gid: 0,
-            mode: 0o755,
-            modified: 0,
-            xattrs: HashMap::new(),
-            flags: 0,
-        },
-    })?;
-
-    let data_inode = tree.add(SqsSourceFile {
-        path: PathBuf::from("/data"),
-        content: SqsSource {
-            data: SqsSourceData::Dir(Box::new(Vec::new().into_iter())),
-            uid: 0,
-            gid: 0,
-            mode: 0o755,
-            modified: 0,
-            xattrs: HashMap::new(),
-            flags: 0,
-        },
-    })?;
-
-    let proc_inode = tree.add(SqsSourceFile {
-        path: PathBuf::from("/proc"),
-        content: SqsSource {
-            data: SqsSourceData::Dir(Box::new(Vec::new().into_iter())),
-            uid: 0,
-            gid: 0,
-            mode: 0o755,
-            modified: 0,
-            xattrs: HashMap::new(),
-            flags: 0,
-        },
-    })?;
-
-    let tmp_inode = tree.add(SqsSourceFile {
-        path: PathBuf::from("/tmp"),
-        content: SqsSource {
-            data: SqsSourceData::Dir(Box::new(Vec::new().into_iter())),
-            uid: 0,
-            gid: 0,
-            mode: 0o755,
-            modified: 0,
-            xattrs: HashMap::new(),
-            flags: 0,
-        },
-    })?;
-
-    let run_inode = tree.add(SqsSourceFile {
-        path: PathBuf::from("/run"),
-        content: SqsSource {
-            data: SqsSourceData::Dir(Box::new(Vec::new().into_iter())),
-            uid: 0,
-            gid: 0,
-            mode: 0o755,
-            modified: 0,
-            xattrs: HashMap::new(),
-            flags: 0,
-        },
-    })?;
-
-    tree.add(SqsSourceFile {
-        path: PathBuf::from("/"),
-        content: SqsSource {
-            data: SqsSourceData::Dir(Box::new(
-                vec![
-                    (OsString::from("bin"), bin_inode),
-                    (OsString::from("dev"), dev_inode),
-                    (OsString::from("boot"), boot_inode),
-                    (OsString::from("data"), data_inode),
-                    (OsString::from("proc"), proc_inode),
-                    (OsString::from("tmp"), tmp_inode),
-                    (OsString::from("run"), run_inode),
-                ]
-                .into_iter(),
-            )),
-            uid: 0,
-            gid: 0,
-            mode: 0o755,
-            modified: 0,
-            xattrs: HashMap::new(),
-            flags: 0,
-        },
-    })?;
-
+    Rootfs::new().write(&tree)?;
     tree.finish()?;
 
-    tmp_file.rewind()?;
-    partition.rewind()?;
-    io::copy(&mut tmp_file, partition)?;
-
-    println!("Root filesystem B created successfully");
-    Ok(())
+    Ok(tmp_file)
 }
 
-fn format_ext4(partition: &mut StreamSlice<File>) -> anyhow::Result<()> {
-    let mut tmp_file = tempfile::NamedTempFile::new()?;
-    io::copy(partition, &mut tmp_file)?;
+fn write_squashfs(
+    partition: &mut StreamSlice<File>,
+    squashfs: &mut File,
+    slot: &str,
+    sparse: bool,
+) -> anyhow::Result<()> {
+    let phase = progress::phase(
+        &format!("root_{}", slot.to_lowercase()),
+        format!("Writing root filesystem {}...", slot),
+    );
 
-    let mut mkfs = no_stdin("mkfs.ext4");
-    mkfs.arg(tmp_file.path());
+    squashfs.rewind()?;
+    let superblock = Superblock::read(squashfs)?;
 
-    if !mkfs.spawn()?.wait()?.success() {
-        bail!("mkfs.ext4 failed");
+    partition.seek(SeekFrom::End(0))?;
+    let partition_len = partition.stream_position()?;
+
+    if superblock.bytes_used > partition_len {
+        bail!(
+            "root filesystem {} ({} bytes) doesn't fit into its partition ({} bytes)",
+            slot,
+            superblock.bytes_used,
+            partition_len
+        );
     }
 
-    tmp_file.rewind()?;
+    squashfs.rewind()?;
     partition.rewind()?;
-    io::copy(&mut tmp_file, partition)?;
 
-    println!("Data filesystem created successfully");
-    Ok(())
-}
+    let mut src = progress::bar(
+        format!("Writing root filesystem {}", slot),
+        superblock.bytes_used,
+        Unit::Bytes,
+    )
+    .wrap(squashfs.take(superblock.bytes_used));
 
-fn write_instance(instance: &str, size: u64, arch: String) -> anyhow::Result<()> {
-    let info = Instance { size, arch };
+    sparse::copy(&mut src, partition, sparse)?;
+    src.finish();
 
-    let mut file = File::create(instance)?;
-    serde_json::to_writer_pretty(&mut file, &info)?;
+    progress::info(format!("Root filesystem {} created successfully", slot));
+    phase.end();
 
     Ok(())
 }
 
-fn overwrite_device(
-    file: &mut File,
-    overwrite: String,
-    instance: String,
-    arch: String,
-    crates: Vec<String>,
-    git: Vec<String>,
-    init: String,
-) -> anyhow::Result<()> {
-    partition_device(file, overwrite, instance, arch, crates, git, init)?;
-    Ok(())
+/// Returns the smallest image size that fits the data partition, rounded up to whole MiB.
+/// The other partitions have a fixed size.
+fn auto_size(image: &Image) -> anyhow::Result<u64> {
+    let size = FIXED_SIZE + data::min_size(&image.config.data)?;
+    Ok(size.div_ceil(MiB as u64) * MiB as u64)
 }
 
 fn overwrite_file(
     file: &mut File,
+    overwrite: String,
     file_size: u64,
     instance: String,
-    arch: String,
-    crates: Vec<String>,
-    git: Vec<String>,
-    init: String,
+    image: Image,
+    format: Format,
 ) -> anyhow::Result<()> {
-    partition(file, file_size, instance, arch, crates, git, init)?;
+    if format == Format::Raw {
+        partition(file, file_size, instance, image, true)?;
+    } else {
+        let mut raw = output::raw_tempfile(&overwrite)?;
+        partition(raw.as_file_mut(), file_size, instance, image, true)?;
+
+        output::convert(raw.path(), &overwrite, format)?;
+    }
+
     Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    match args.arch.as_str() {
-        "x86_64" => {}
-        "rpi" => {}
-        _ => bail!("invalid architecture (supported: x86_64 rpi)"),
-    }
+    let level = if args.quiet {
+        Level::Quiet
+    } else if args.verbose {
+        Level::Verbose
+    } else {
+        Level::Normal
+    };
 
-    let init_in_crates = args.crates.iter().any(|pkg| *pkg == args.init);
-    let init_in_git = args.git.iter().any(|location| {
-        let mut split = location.split('%');
+    progress::init(level, args.json);
 
-        let url = match Url::parse(split.next().unwrap()) {
-            Ok(url) => url,
-            Err(e) => {
-                println!("Invalid git crate {}: {}", location, e);
-                return false;
-            }
-        };
+    let start = Instant::now();
 
-        let pkg = split.next().unwrap_or(
-            url.path_segments()
-                .unwrap()
-                .next_back()
-                .unwrap()
-                .trim_end_matches(".git"),
-        );
+    let (action, output, result) = match args.action {
+        Some(Action::Inspect(args)) => ("inspect", None, inspect::inspect(args)),
+        Some(Action::Verify(args)) => ("verify", None, verify::verify(args)),
+        Some(Action::Push(args)) => ("push", None, push::push(args)),
+        Some(Action::Run(args)) => ("run", None, qemu::run(args)),
+        Some(Action::TestBoot(args)) => ("test-boot", None, qemu::test_boot(args)),
+        None => {
+            let output = args.overwrite.clone().unwrap_or_default();
+            ("build", Some(output), build(args))
+        }
+    };
 
-        pkg == args.init
-    });
+    progress::summary(action, output.as_deref(), start, &result);
+
+    result
+}
+
+fn build(args: Args) -> anyhow::Result<()> {
+    let from = match &args.image.from_instance {
+        Some(path) => Some(Instance::load(path)?),
+        None => None,
+    };
+
+    // Only optional if a subcommand or an instance file is used.
+    let overwrite = args.overwrite.unwrap();
+    let instance = args
+        .instance
+        .or_else(|| args.image.from_instance.clone())
+        .unwrap();
 
-    if !init_in_crates && !init_in_git {
-        bail!("Init must be listed in crates to install");
+    let size = merge_recorded(args.size, from.as_ref().map(|from| from.size), "size")?;
+    let image = resolve_image(args.image, from.as_ref())?;
+
+    if args.dry_run {
+        return plan::print(&overwrite, size, &image, args.format);
     }
 
+    let device = device::identify(&overwrite)?;
+
+    match &device {
+        Some(device) => {
+            device.check_unused()?;
+
+            if !args.yes {
+                device::confirm(&overwrite, device)?;
+            }
+        }
+        // A typo in a device path shouldn't silently create a file in /dev.
+        None if overwrite.starts_with("/dev/") && !Path::new(&overwrite).exists() => {
+            bail!("{} doesn't exist", overwrite);
+        }
+        None => {}
+    }
+
+    // partition() discards the old contents of image files itself.
     let mut file = OpenOptions::new()
         .read(true)
         .write(true)
         .create(true)
-        .open(args.overwrite.clone())?;
-
-    if file.metadata()?.permissions().mode() & MODE_DEVICE != 0 {
-        overwrite_device(
-            &mut file,
-            args.overwrite,
-            args.instance,
-            args.arch,
-            args.crates,
-            args.git,
-            args.init,
-        )
+        .truncate(false)
+        .open(&overwrite)?;
+
+    if device.is_some() {
+        if args.format != Format::Raw {
+            bail!("Devices can only be written in raw format");
+        }
+
+        partition_device(&mut file, overwrite, size, instance, image)
     } else {
-        match args.size {
-            Some(v) => overwrite_file(
-                &mut file,
-                v,
-                args.instance,
-                args.arch,
-                args.crates,
-                args.git,
-                args.init,
-            ),
-            None => bail!("Files require --size to be specified"),
+        let size = match size {
+            Some(v) => v,
+            None => {
+                let size = auto_size(&image)?;
+                progress::info(format!("Image size: {} bytes", size));
+
+                size
+            }
+        };
+
+        overwrite_file(&mut file, overwrite, size, instance, image, args.format)
+    }
+}
+
+/// Resolves the contents of the image from the command line
+/// and the instance file of an existing device.
+fn resolve_image(args: ImageArgs, from: Option<&Instance>) -> anyhow::Result<Image> {
+    // --architecture is required if no instance file is used.
+    let arch = merge_recorded(
+        args.arch,
+        from.map(|from| from.arch.clone()),
+        "architecture",
+    )?
+    .unwrap();
+
+    let mut crates = args.crates;
+    let mut git = args.git;
+    let mut binaries = args.binaries;
+    let mut init = args.init;
+
+    // The recorded crates and binaries are only used if none are specified.
+    if let Some(from) = from {
+        if crates.is_empty() && git.is_empty() && binaries.is_empty() {
+            let (recorded_crates, recorded_git, recorded_init) =
+                from.crate_args(args.update_crates);
+
+            crates = recorded_crates;
+            git = recorded_git;
+            binaries = from.binary_args();
+            init = init.or(recorded_init);
         }
     }
+
+    let binaries = binaries
+        .iter()
+        .map(|spec| prebuilt::Binary::parse(spec))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let init = match init {
+        Some(init) => init,
+        None => bail!("--init is required because the instance file doesn't record one"),
+    };
+
+    match arch.as_str() {
+        "x86_64" => {}
+        "rpi" => {}
+        _ => bail!("invalid architecture (supported: x86_64 rpi)"),
+    }
+
+    let init_in_crates = crates.iter().any(|spec| crate_spec(spec).0 == init);
+    let init_in_git = git.iter().any(|location| match git_crate(location) {
+        Ok((_, pkg)) => pkg == init,
+        Err(e) => {
+            progress::warn(format!("Invalid git crate {}: {}", location, e));
+            false
+        }
+    });
+
+    let init_in_binaries = binaries.iter().any(|binary| binary.name == init);
+
+    if !init_in_crates && !init_in_git && !init_in_binaries {
+        bail!("Init must be listed in crates or binaries to install");
+    }
+
+    let config = match args.config {
+        Some(path) => Config::load(&path)?,
+        None => from
+            .and_then(|from| from.config.clone())
+            .unwrap_or_default(),
+    };
+
+    Ok(Image {
+        arch,
+        crates,
+        git,
+        binaries,
+        init,
+        config,
+        root_b: args.root_b,
+        grow_data: args.grow_on_first_boot,
+        update: from.and_then(|from| from.update.clone()),
+        cache: args.cache.dir()?,
+        kernel_args: None,
+    })
+}
+
+/// Returns the value given on the command line or the one recorded in the instance file.
+/// Fails if both are present and disagree.
+fn merge_recorded<T: PartialEq + std::fmt::Display>(
+    arg: Option<T>,
+    recorded: Option<T>,
+    name: &str,
+) -> anyhow::Result<Option<T>> {
+    match (arg, recorded) {
+        (Some(arg), Some(recorded)) if arg != recorded => bail!(
+            "{} {} disagrees with the instance file ({})",
+            name,
+            arg,
+            recorded
+        ),
+        (arg, recorded) => Ok(arg.or(recorded)),
+    }
 }
 
 fn no_stdin<S: AsRef<OsStr>>(program: S) -> Command {
     let mut cmd = Command::new(program);
     cmd.stdin(Stdio::null());
 
+    // Keep stdout free for JSON events.
+    if progress::json() {
+        cmd.stdout(io::stderr());
+    }
+
     cmd
 }
+
+fn run(cmd: &mut Command) -> anyhow::Result<()> {
+    if !cmd.spawn()?.wait()?.success() {
+        bail!("{} failed", cmd.get_program().to_string_lossy());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn append_cmdline_keeps_one_line() {
+        assert_eq!(
+            append_cmdline(b"root=/dev/sda2 init=/bin/init\n", "console=ttyS0,115200"),
+            b"root=/dev/sda2 init=/bin/init console=ttyS0,115200\n"
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use anyhow::bail;
+use fscommon::StreamSlice;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{prelude::*, SeekFrom};
+
+pub const SECTOR_SIZE: u64 = 512;
+
+/// Partition roles in the order the packer writes them.
+const ROLES: [&str; 4] = ["boot", "root A", "root B", "data"];
+
+/// A partition table entry.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Partition {
+    pub active: bool,
+    #[serde(rename = "type")]
+    pub kind: u8,
+    pub start_lba: u32,
+    pub sectors: u32,
+}
+
+impl Partition {
+    pub fn start(&self) -> u64 {
+        self.start_lba as u64 * SECTOR_SIZE
+    }
+
+    pub fn size(&self) -> u64 {
+        self.sectors as u64 * SECTOR_SIZE
+    }
+
+    /// Returns the partition as a stream of its own.
+    pub fn open(&self, file: &File) -> anyhow::Result<StreamSlice<File>> {
+        if self.sectors == 0 {
+            bail!("partition is empty");
+        }
+
+        Ok(StreamSlice::new(
+            file.try_clone()?,
+            self.start(),
+            self.start() + self.size() - 1,
+        )?)
+    }
+}
+
+/// A used partition table entry and what the packer uses it for.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LayoutEntry {
+    pub number: usize,
+    pub role: String,
+    #[serde(flatten)]
+    pub partition: Partition,
+}
+
+/// The first sector of an image as written by the packer.
+#[derive(Clone, Debug)]
+pub struct Mbr {
+    /// LBA of the kernel (/vmlinuz on the boot partition), used by the x86_64 bootloader.
+    pub kernel_lba: u32,
+    /// LBA of the kernel command line (/cmdline.txt on the boot partition).
+    pub cmdline_lba: u32,
+    /// Unused entries are `None`.
+    pub partitions: [Option<Partition>; 4],
+    pub signature_valid: bool,
+}
+
+impl Mbr {
+    pub fn read(file: &mut File) -> anyhow::Result<Self> {
+        let mut buf = [0; SECTOR_SIZE as usize];
+
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut buf)?;
+
+        Self::parse(&buf)
+    }
+
+    /// Parses the first sector of an image.
+    pub fn parse(buf: &[u8; SECTOR_SIZE as usize]) -> anyhow::Result<Self> {
+        let mut partitions = [None; 4];
+        for (i, partition) in partitions.iter_mut().enumerate() {
+            let entry = &buf[446 + i * 16..446 + (i + 1) * 16];
+
+            if entry.iter().any(|b| *b != 0) {
+                *partition = Some(Partition {
+                    active: entry[0] == 0x80,
+                    kind: entry[4],
+                    start_lba: u32::from_le_bytes(entry[8..12].try_into()?),
+                    sectors: u32::from_le_bytes(entry[12..16].try_into()?),
+                });
+            }
+        }
+
+        Ok(Self {
+            kernel_lba: u32::from_le_bytes(buf[432..436].try_into()?),
+            cmdline_lba: u32::from_le_bytes(buf[436..440].try_into()?),
+            partitions,
+            signature_valid: buf[510..512] == [0x55, 0xAA],
+        })
+    }
+
+    /// Returns the used partitions.
+    pub fn layout(&self) -> Vec<LayoutEntry> {
+        self.partitions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, partition)| {
+                partition.map(|partition| LayoutEntry {
+                    number: i + 1,
+                    role: ROLES[i].to_owned(),
+                    partition,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Returns the size of a device or image file.
+pub fn size(file: &mut File) -> anyhow::Result<u64> {
+    Ok(file.seek(SeekFrom::End(0))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_layout() {
+        let mut buf = [0; SECTOR_SIZE as usize];
+        buf[432..436].copy_from_slice(&2100u32.to_le_bytes());
+        buf[436..440].copy_from_slice(&2200u32.to_le_bytes());
+
+        // boot (active, FAT32 LBA) and root A (Linux), the rest unused.
+        buf[446] = 0x80;
+        buf[446 + 4] = 0x0c;
+        buf[446 + 8..446 + 12].copy_from_slice(&2048u32.to_le_bytes());
+        buf[446 + 12..446 + 16].copy_from_slice(&262144u32.to_le_bytes());
+        buf[462 + 4] = 0x83;
+        buf[462 + 8..462 + 12].copy_from_slice(&264192u32.to_le_bytes());
+        buf[462 + 12..462 + 16].copy_from_slice(&524288u32.to_le_bytes());
+        buf[510] = 0x55;
+        buf[511] = 0xAA;
+
+        let mbr = Mbr::parse(&buf).unwrap();
+        assert_eq!(mbr.kernel_lba, 2100);
+        assert_eq!(mbr.cmdline_lba, 2200);
+        assert!(mbr.signature_valid);
+        assert!(mbr.partitions[2].is_none() && mbr.partitions[3].is_none());
+
+        let layout = mbr.layout();
+        assert_eq!(layout.len(), 2);
+        assert_eq!((layout[0].number, layout[0].role.as_str()), (1, "boot"));
+        assert!(layout[0].partition.active);
+        assert_eq!(layout[0].partition.kind, 0x0c);
+        assert_eq!(layout[0].partition.start(), 2048 * SECTOR_SIZE);
+        assert_eq!((layout[1].number, layout[1].role.as_str()), (2, "root A"));
+        assert!(!layout[1].partition.active);
+        assert_eq!(layout[1].partition.size(), 524288 * SECTOR_SIZE);
+    }
+
+    #[test]
+    fn parse_missing_signature() {
+        let mbr = Mbr::parse(&[0; SECTOR_SIZE as usize]).unwrap();
+        assert!(!mbr.signature_valid);
+        assert!(mbr.layout().is_empty());
+    }
+}
@@ -0,0 +1,105 @@
+use crate::progress;
+
+use anyhow::bail;
+use clap::ValueEnum;
+use std::fs::File;
+use std::path::Path;
+use tempfile::NamedTempFile;
+
+/// The file format an image is written in.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum Format {
+    /// Plain disk image, suitable for dd.
+    #[default]
+    Raw,
+    /// zstd compressed disk image (.img.zst).
+    Zst,
+    /// xz compressed disk image (.img.xz).
+    Xz,
+    /// QEMU copy-on-write image.
+    Qcow2,
+    /// VMware disk image.
+    Vmdk,
+    /// Hyper-V / Virtual PC disk image.
+    Vhd,
+    /// Hyper-V disk image.
+    Vhdx,
+}
+
+impl Format {
+    fn qemu_img_format(&self) -> Option<&'static str> {
+        match self {
+            Self::Raw | Self::Zst | Self::Xz => None,
+            Self::Qcow2 => Some("qcow2"),
+            Self::Vmdk => Some("vmdk"),
+            Self::Vhd => Some("vpc"),
+            Self::Vhdx => Some("vhdx"),
+        }
+    }
+}
+
+/// Creates the temporary raw image that is converted to `dst` later.
+/// It lives next to `dst` because /tmp is often too small for a full image.
+pub fn raw_tempfile(dst: &str) -> anyhow::Result<NamedTempFile> {
+    let dir = match Path::new(dst).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    Ok(NamedTempFile::new_in(dir)?)
+}
+
+/// Converts the raw image at `src` to `dst` in the requested format.
+pub fn convert(src: &Path, dst: &str, format: Format) -> anyhow::Result<()> {
+    let phase = progress::phase(
+        "convert",
+        format!(
+            "Converting image to {}...",
+            format!("{:?}", format).to_lowercase()
+        ),
+    );
+
+    match format {
+        Format::Raw => bail!("raw images don't need to be converted"),
+        Format::Zst => {
+            let mut zstd = crate::no_stdin("zstd");
+            zstd.arg("-q")
+                .arg("-f")
+                .arg("-T0")
+                .arg(src)
+                .arg("-o")
+                .arg(dst);
+
+            crate::run(&mut zstd)?;
+        }
+        Format::Xz => {
+            let mut xz = crate::no_stdin("xz");
+            xz.arg("-q")
+                .arg("-c")
+                .arg("-T0")
+                .arg(src)
+                .stdout(File::create(dst)?);
+
+            crate::run(&mut xz)?;
+        }
+        _ => {
+            let mut qemu_img = crate::no_stdin("qemu-img");
+            qemu_img
+                .arg("convert")
+                .arg("-q")
+                .arg("-f")
+                .arg("raw")
+                .arg("-O")
+                .arg(format.qemu_img_format().unwrap())
+                .arg(src)
+                .arg(dst);
+
+            crate::run(&mut qemu_img)?;
+        }
+    }
+
+    progress::info("Image converted successfully");
+    phase.end();
+
+    Ok(())
+}
@@ -0,0 +1,370 @@
+use crate::data;
+use crate::device;
+use crate::mbr::{Mbr, SECTOR_SIZE};
+use crate::output::Format;
+use crate::prebuilt::Source;
+use crate::rootfs::{Contents, Entry, Kind, Rootfs};
+use crate::{Image, MiB, RootB, FIRMWARE_BASE, KERNEL_BASE, RPI_DTBS, RPI_FIRMWARE};
+
+use anyhow::{anyhow, bail};
+use reqwest::Url;
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Sparse crates.io index as used by cargo.
+const INDEX_BASE: &str = "https://index.crates.io/";
+
+/// A line of a crates.io index file.
+#[derive(Debug, Deserialize)]
+struct IndexEntry {
+    vers: String,
+    yanked: bool,
+}
+
+/// Prints what writing `image` to `overwrite` would do without writing anything.
+/// Only the crate index and git repositories are queried, nothing is built or downloaded.
+pub fn print(
+    overwrite: &str,
+    size: Option<u64>,
+    image: &Image,
+    format: Format,
+) -> anyhow::Result<()> {
+    let device = device::identify(overwrite)?;
+
+    println!("Target:");
+
+    let size = match &device {
+        Some(device) => {
+            if format != Format::Raw {
+                bail!("Devices can only be written in raw format");
+            }
+
+            if let Some(expected) = size {
+                if expected != device.size {
+                    bail!(
+                        "destination holds {} bytes but the instance file says {}",
+                        device.size,
+                        expected
+                    );
+                }
+            }
+
+            println!(
+                "  {}: {}, {} bytes",
+                overwrite,
+                device.description(),
+                device.size
+            );
+
+            if let Err(e) = device.check_unused() {
+                println!("  Can't be written: {}", e);
+            }
+
+            device.size
+        }
+        None => {
+            let size = match size {
+                Some(v) => v,
+                None => crate::auto_size(image)?,
+            };
+
+            let state = if Path::new(overwrite).exists() {
+                "existing file, replaced"
+            } else {
+                "new file"
+            };
+
+            println!(
+                "  {}: {}, {} bytes, {} format",
+                overwrite,
+                state,
+                size,
+                format!("{:?}", format).to_lowercase()
+            );
+
+            size
+        }
+    };
+
+    crate::check_size(size, image)?;
+
+    print_layout(size, image)?;
+    print_boot_files(image);
+    print_crates(image);
+    print_root(image)?;
+
+    Ok(())
+}
+
+fn print_layout(size: u64, image: &Image) -> anyhow::Result<()> {
+    let mut buf = Vec::new();
+    crate::write_mbr_partition_table(&mut buf, size, image.config.data.filesystem)?;
+
+    let mbr = Mbr::parse(buf.as_slice().try_into()?)?;
+
+    println!();
+    println!("Partitions:");
+    for p in mbr.layout() {
+        println!(
+            "  {} {:<7} type 0x{:02x}  LBA {:>9} - {:>9}  {:>8} MiB",
+            p.number,
+            p.role,
+            p.partition.kind,
+            p.partition.start_lba,
+            p.partition.start_lba as u64 + p.partition.sectors as u64 - 1,
+            p.partition.sectors as u64 * SECTOR_SIZE / MiB as u64
+        );
+    }
+
+    let data = &image.config.data;
+    if data.filesystem.partition_type().is_some() {
+        println!(
+            "Data filesystem: {}, needs at least {} bytes",
+            format!("{:?}", data.filesystem).to_lowercase(),
+            data::min_size(data)?
+        );
+    }
+
+    Ok(())
+}
+
+fn print_boot_files(image: &Image) {
+    println!();
+    println!("Boot files:");
+
+    println!(
+        "  {:<28} <- {}vmlinuz-{}",
+        "vmlinuz", KERNEL_BASE, image.arch
+    );
+    println!("  {:<28} <- {}cmdline.txt", "cmdline.txt", KERNEL_BASE);
+    println!("  {:<28} <- {}config.txt", "config.txt", KERNEL_BASE);
+
+    if image.grow_data {
+        println!("  {:<28} (empty marker)", "grow-data");
+    }
+
+    if image.arch == "rpi" {
+        for dtb in RPI_DTBS {
+            println!("  {:<28} <- {}{}", dtb, KERNEL_BASE, dtb);
+        }
+
+        for fw in RPI_FIRMWARE {
+            println!("  {:<28} <- {}{}", fw, FIRMWARE_BASE, fw);
+        }
+    }
+}
+
+fn print_crates(image: &Image) {
+    println!();
+    println!("Crates (estimated, cargo resolves the versions when building):");
+
+    for spec in &image.crates {
+        let name = match crate::crate_spec(spec) {
+            (name, Some(version)) => {
+                println!("  {} {} (crates.io, requested)", name, version);
+                continue;
+            }
+            (name, None) => name,
+        };
+
+        match latest_version(name) {
+            Ok(version) => println!("  {} {} (crates.io, latest release)", name, version),
+            Err(e) => println!("  {} (crates.io, can't resolve version: {})", name, e),
+        }
+    }
+
+    for location in &image.git {
+        let result =
+            crate::git_crate(location).and_then(|(url, pkg)| Ok((latest_commit(&url)?, url, pkg)));
+
+        match result {
+            Ok((commit, url, pkg)) => println!("  {} {} ({})", pkg, commit, url),
+            Err(e) => println!("  {} (can't resolve commit: {})", location, e),
+        }
+    }
+
+    if !image.binaries.is_empty() {
+        println!();
+        println!("Prebuilt binaries:");
+
+        for binary in &image.binaries {
+            match &binary.source {
+                Source::Path(_) => println!("  {} <- {}", binary.name, binary.location()),
+                Source::Url { url, sha256 } => {
+                    println!("  {} <- {} (sha256 {})", binary.name, url, sha256)
+                }
+            }
+        }
+    }
+}
+
+fn print_root(image: &Image) -> anyhow::Result<()> {
+    // The binaries don't exist yet, so their sizes are unknown.
+    let mut binaries = BTreeSet::new();
+    for spec in &image.crates {
+        binaries.insert(binary_path(crate::crate_spec(spec).0, &image.init));
+    }
+    for location in &image.git {
+        binaries.insert(binary_path(&crate::git_crate(location)?.1, &image.init));
+    }
+
+    let prebuilt: BTreeSet<PathBuf> = image
+        .binaries
+        .iter()
+        .map(|binary| binary_path(&binary.name, &image.init))
+        .collect();
+
+    let mut rootfs = Rootfs::new();
+    for path in binaries.iter().chain(&prebuilt) {
+        rootfs.insert(path, Entry::executable(Contents::Bytes(Vec::new())))?;
+    }
+
+    crate::add_config(&mut rootfs, image)?;
+
+    println!();
+    println!("Root filesystem A:");
+
+    let mut size = 0;
+    for (path, entry) in rootfs.entries() {
+        let (kind, len, target) = match &entry.kind {
+            Kind::Dir => ('d', String::new(), None),
+            Kind::File(_) if binaries.contains(path) => ('-', String::from("(built)"), None),
+            Kind::File(_) if prebuilt.contains(path) => ('-', String::from("(prebuilt)"), None),
+            Kind::File(contents) => {
+                let len = contents.size()?;
+                size += len;
+
+                ('-', len.to_string(), None)
+            }
+            Kind::Symlink(target) => ('l', String::new(), Some(target)),
+            Kind::Hardlink(target) => ('h', String::new(), Some(target)),
+            Kind::CharDev(major, minor) => ('c', format!("{}, {}", major, minor), None),
+            Kind::BlockDev(major, minor) => ('b', format!("{}, {}", major, minor), None),
+        };
+
+        match target {
+            Some(target) => println!(
+                "  {}{:04o} {:>5}:{:<5} {:>10} {} -> {}",
+                kind,
+                entry.mode,
+                entry.uid,
+                entry.gid,
+                len,
+                path.display(),
+                target.display()
+            ),
+            None => println!(
+                "  {}{:04o} {:>5}:{:<5} {:>10} {}",
+                kind,
+                entry.mode,
+                entry.uid,
+                entry.gid,
+                len,
+                path.display()
+            ),
+        }
+    }
+
+    println!(
+        "Estimated size: {} bytes uncompressed plus {} binaries",
+        size,
+        binaries.len() + prebuilt.len()
+    );
+
+    println!();
+    match &image.root_b {
+        RootB::Empty => println!("Root filesystem B: directory skeleton"),
+        RootB::Same => println!("Root filesystem B: copy of A"),
+        RootB::Image(path) => println!(
+            "Root filesystem B: {} ({} bytes)",
+            path.display(),
+            fs::metadata(path)
+                .map_err(|e| anyhow!("can't open {}: {}", path.display(), e))?
+                .len()
+        ),
+    }
+
+    Ok(())
+}
+
+/// Returns the location of a crate's binary in the root filesystem.
+fn binary_path(pkg: &str, init: &str) -> PathBuf {
+    Path::new("/bin").join(if pkg == init { "init" } else { pkg })
+}
+
+/// Estimates the version `cargo install` would pick: the highest release that isn't yanked.
+/// Unlike cargo's resolver this ignores the rust-version of releases.
+fn latest_version(name: &str) -> anyhow::Result<String> {
+    let name = name.to_lowercase();
+
+    let dir = match name.len() {
+        0 => bail!("empty crate name"),
+        1 => String::from("1"),
+        2 => String::from("2"),
+        3 => format!("3/{}", &name[..1]),
+        _ => format!("{}/{}", &name[..2], &name[2..4]),
+    };
+
+    let index = reqwest::blocking::get(format!("{}{}/{}", INDEX_BASE, dir, name))?
+        .error_for_status()?
+        .text()?;
+
+    index
+        .lines()
+        .filter_map(|line| serde_json::from_str::<IndexEntry>(line).ok())
+        .filter(|entry| !entry.yanked && !entry.vers.contains('-'))
+        .max_by_key(|entry| version_key(&entry.vers))
+        .map(|entry| entry.vers)
+        .ok_or_else(|| anyhow!("no releases"))
+}
+
+/// Orders release versions numerically, e.g. 0.10.0 after 0.9.3.
+/// Pre-releases are filtered out beforehand.
+fn version_key(version: &str) -> Vec<u64> {
+    version
+        .split('+')
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map(|n| n.parse().unwrap_or(0))
+        .collect()
+}
+
+/// Returns the commit the branch, tag or HEAD of a git crate currently points to.
+/// Cargo checks out the same commit unless the repository changes before the build.
+fn latest_commit(url: &Url) -> anyhow::Result<String> {
+    let mut reference = String::from("HEAD");
+
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "branch" => reference = format!("refs/heads/{}", value),
+            "tag" => reference = format!("refs/tags/{}", value),
+            "rev" => return Ok(value.into_owned()),
+            _ => {}
+        }
+    }
+
+    let mut repo = url.clone();
+    repo.set_query(None);
+    repo.set_fragment(None);
+
+    let output = crate::no_stdin("git")
+        .arg("ls-remote")
+        .arg(repo.as_str())
+        .arg(&reference)
+        .output()?;
+
+    if !output.status.success() {
+        bail!("git ls-remote failed");
+    }
+
+    match String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+    {
+        Some(commit) => Ok(commit.to_owned()),
+        None => bail!("{} not found", reference),
+    }
+}
@@ -0,0 +1,176 @@
+use crate::elf;
+use crate::instance;
+
+use anyhow::{anyhow, bail};
+use reqwest::Url;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A prebuilt binary given with --binary ("<path or URL>[%<name>]").
+#[derive(Clone, Debug)]
+pub struct Binary {
+    /// File name in /bin.
+    pub name: String,
+    pub source: Source,
+}
+
+#[derive(Clone, Debug)]
+pub enum Source {
+    Path(PathBuf),
+    /// Downloaded and checked against the SHA-256 digest
+    /// given in the fragment of the URL ("#sha256=<hex>").
+    Url {
+        url: Url,
+        sha256: String,
+    },
+}
+
+impl Binary {
+    /// Parses a --binary argument. The name defaults to the file name.
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let is_url = spec.starts_with("https://") || spec.starts_with("http://");
+
+        // URLs can contain percent-encoded characters,
+        // so their name can only follow the checksum fragment.
+        let (location, name) = match spec.rsplit_once('%') {
+            Some((location, name))
+                if !name.contains('/') && (!is_url || location.contains('#')) =>
+            {
+                (location, Some(name))
+            }
+            _ => (spec, None),
+        };
+
+        let source = if is_url {
+            let mut url = Url::parse(location)?;
+
+            let sha256 = match url.fragment().and_then(|f| f.strip_prefix("sha256=")) {
+                Some(sha256)
+                    if sha256.len() == 64 && sha256.chars().all(|c| c.is_ascii_hexdigit()) =>
+                {
+                    sha256.to_lowercase()
+                }
+                _ => bail!(
+                    "{}: URLs need a checksum, append #sha256=<hex digest>",
+                    location
+                ),
+            };
+            url.set_fragment(None);
+
+            Source::Url { url, sha256 }
+        } else {
+            Source::Path(PathBuf::from(location))
+        };
+
+        let name = match name {
+            Some(name) => name.to_owned(),
+            None => match &source {
+                Source::Path(path) => path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                Source::Url { url, .. } => url
+                    .path_segments()
+                    .and_then(|mut segments| segments.next_back())
+                    .unwrap_or_default()
+                    .to_owned(),
+            },
+        };
+
+        if name.is_empty() || name.contains('/') {
+            bail!("{}: invalid binary name {:?}", spec, name);
+        }
+
+        Ok(Self { name, source })
+    }
+
+    /// Returns the host path or URL as recorded in the instance file.
+    pub fn location(&self) -> String {
+        match &self.source {
+            Source::Path(path) => fs::canonicalize(path)
+                .unwrap_or_else(|_| path.clone())
+                .to_string_lossy()
+                .into_owned(),
+            Source::Url { url, .. } => url.to_string(),
+        }
+    }
+
+    /// Downloads the binary into `dir` if necessary and checks that it is
+    /// a static executable for `arch`. Returns its location and SHA-256 digest.
+    pub fn fetch(&self, arch: &str, dir: &Path) -> anyhow::Result<(PathBuf, String)> {
+        let (path, contents) = match &self.source {
+            Source::Path(path) => {
+                let contents =
+                    fs::read(path).map_err(|e| anyhow!("can't read {}: {}", path.display(), e))?;
+
+                (path.clone(), contents)
+            }
+            Source::Url { url, sha256 } => {
+                let contents = crate::download(url.as_str(), &self.name)?;
+
+                let digest = instance::sha256(&contents);
+                if digest != *sha256 {
+                    bail!(
+                        "checksum mismatch for {}: expected {}, got {}",
+                        url,
+                        sha256,
+                        digest
+                    );
+                }
+
+                let path = dir.join(&self.name);
+                fs::write(&path, &contents)?;
+
+                (path, contents)
+            }
+        };
+
+        elf::check(&contents, arch)
+            .and_then(|_| elf::check_static(&contents))
+            .map_err(|e| anyhow!("{}: {}", self.name, e))?;
+
+        Ok((path, instance::sha256(&contents)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn url(binary: &Binary) -> &str {
+        match &binary.source {
+            Source::Url { url, .. } => url.as_str(),
+            Source::Path(_) => panic!("not a URL: {:?}", binary),
+        }
+    }
+
+    #[test]
+    fn parse_percent_encoded_url() {
+        let spec = format!("https://example.com/my%20tool#sha256={}", SHA256);
+        let binary = Binary::parse(&spec).unwrap();
+        assert_eq!(url(&binary), "https://example.com/my%20tool");
+
+        let spec = format!("https://example.com/my%20tool#sha256={}%tool", SHA256);
+        let binary = Binary::parse(&spec).unwrap();
+        assert_eq!(url(&binary), "https://example.com/my%20tool");
+        assert_eq!(binary.name, "tool");
+    }
+
+    #[test]
+    fn parse_path() {
+        let binary = Binary::parse("out/busybox%sh").unwrap();
+        assert_eq!(binary.name, "sh");
+        assert!(matches!(binary.source, Source::Path(path) if path == Path::new("out/busybox")));
+
+        let binary = Binary::parse("out%1/busybox").unwrap();
+        assert_eq!(binary.name, "busybox");
+    }
+
+    #[test]
+    fn parse_requires_checksum() {
+        assert!(Binary::parse("https://example.com/tool").is_err());
+        assert!(Binary::parse("https://example.com/tool#sha256=1234%tool").is_err());
+    }
+}
@@ -0,0 +1,326 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::io::{self, prelude::*, IsTerminal};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::time::{Duration, Instant};
+
+/// Minimum time between two redraws of a progress bar or two JSON progress events.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(250);
+
+const BAR_WIDTH: u64 = 30;
+
+/// How much human readable output to print.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Warnings only.
+    Quiet,
+    Normal,
+    /// Also details like individual firmware files and the output of cargo.
+    Verbose,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Normal as u8);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Selects the output for the rest of the program.
+/// With `json` set, stdout only receives one JSON event per line.
+pub fn init(level: Level, json: bool) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    JSON.store(json, Ordering::Relaxed);
+}
+
+pub fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => Level::Quiet,
+        1 => Level::Normal,
+        _ => Level::Verbose,
+    }
+}
+
+pub fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+fn emit(event: &str, mut fields: Value) {
+    fields["event"] = Value::from(event);
+    println!("{}", fields);
+}
+
+fn message(level: Level, kind: &str, text: &str) {
+    if self::level() < level {
+        return;
+    }
+
+    if json() {
+        emit("message", json!({ "level": kind, "text": text }));
+    } else {
+        println!("{}", text);
+    }
+}
+
+/// Reports a status message.
+pub fn info<S: AsRef<str>>(text: S) {
+    message(Level::Normal, "info", text.as_ref());
+}
+
+/// Reports a message that is only interesting with --verbose.
+pub fn detail<S: AsRef<str>>(text: S) {
+    message(Level::Verbose, "detail", text.as_ref());
+}
+
+/// Reports a problem that doesn't stop the build. Shown even with --quiet.
+pub fn warn<S: AsRef<str>>(text: S) {
+    if json() {
+        emit(
+            "message",
+            json!({ "level": "warning", "text": text.as_ref() }),
+        );
+    } else {
+        eprintln!("Warning: {}", text.as_ref());
+    }
+}
+
+/// A step of the build, e.g. creating the boot filesystem.
+#[derive(Debug)]
+#[must_use]
+pub struct Phase {
+    name: String,
+    start: Instant,
+}
+
+/// Starts a phase. `text` is shown to humans, `name` identifies it in JSON events.
+pub fn phase<S: AsRef<str>>(name: &str, text: S) -> Phase {
+    if json() {
+        emit(
+            "phase_start",
+            json!({ "phase": name, "text": text.as_ref() }),
+        );
+    } else {
+        info(text);
+    }
+
+    Phase {
+        name: name.to_owned(),
+        start: Instant::now(),
+    }
+}
+
+impl Phase {
+    pub fn end(self) {
+        let seconds = self.start.elapsed().as_secs_f64();
+
+        if json() {
+            emit(
+                "phase_end",
+                json!({ "phase": self.name, "seconds": seconds }),
+            );
+        } else {
+            detail(format!("{} took {:.1} s", self.name, seconds));
+        }
+    }
+}
+
+/// Reports the result of building a crate.
+pub fn crate_built(name: &str, duration: Duration, result: &anyhow::Result<()>) {
+    let seconds = duration.as_secs_f64();
+
+    if json() {
+        emit(
+            "crate",
+            match result {
+                Ok(_) => json!({ "name": name, "result": "ok", "seconds": seconds }),
+                Err(e) => json!({
+                    "name": name,
+                    "result": "error",
+                    "error": e.to_string(),
+                    "seconds": seconds,
+                }),
+            },
+        );
+    } else if result.is_ok() {
+        info(format!("Built {} in {:.1} s", name, seconds));
+    }
+}
+
+/// Reports the outcome of a build or subcommand (`action`).
+/// `output` is the image written by a build.
+pub fn summary(action: &str, output: Option<&str>, start: Instant, result: &anyhow::Result<()>) {
+    let seconds = start.elapsed().as_secs_f64();
+
+    if json() {
+        let mut fields = match result {
+            Ok(_) => json!({ "action": action, "result": "ok", "seconds": seconds }),
+            Err(e) => json!({
+                "action": action,
+                "result": "error",
+                "error": e.to_string(),
+                "seconds": seconds,
+            }),
+        };
+
+        if let Some(output) = output {
+            fields["output"] = Value::from(output);
+        }
+
+        emit("summary", fields);
+    } else if let (Some(output), Ok(_)) = (output, result) {
+        info(format!("{} written in {:.1} s", output, seconds));
+    }
+}
+
+/// Emits the report of `inspect` as a JSON event.
+pub fn report<T: Serialize>(report: &T) -> anyhow::Result<()> {
+    emit("report", serde_json::to_value(report)?);
+    Ok(())
+}
+
+/// Reports the outcome of a check made by `verify`. Failures are shown even with --quiet.
+pub fn check(name: &str, result: &anyhow::Result<()>) {
+    if json() {
+        emit(
+            "check",
+            match result {
+                Ok(_) => json!({ "name": name, "result": "ok" }),
+                Err(e) => json!({ "name": name, "result": "error", "error": e.to_string() }),
+            },
+        );
+    } else {
+        match result {
+            Ok(_) if level() >= Level::Normal => println!("ok    {}", name),
+            Ok(_) => {}
+            Err(e) => println!("FAIL  {}: {}", name, e),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unit {
+    Bytes,
+    Items,
+}
+
+/// Progress of a task of known size, e.g. a download.
+/// Drawn on stderr if it is a terminal, reported as JSON events otherwise.
+#[derive(Debug)]
+pub struct Bar {
+    task: String,
+    unit: Unit,
+    total: u64,
+    done: u64,
+    item: String,
+    last_update: Option<Instant>,
+    draw: bool,
+}
+
+pub fn bar<S: Into<String>>(task: S, total: u64, unit: Unit) -> Bar {
+    Bar {
+        task: task.into(),
+        unit,
+        total,
+        done: 0,
+        item: String::new(),
+        last_update: None,
+        draw: !json() && level() >= Level::Normal && io::stderr().is_terminal(),
+    }
+}
+
+impl Bar {
+    pub fn inc(&mut self, n: u64) {
+        self.done += n;
+        self.update(false);
+    }
+
+    /// Shows what is being worked on, e.g. the crate that is being built.
+    pub fn set_item<S: Into<String>>(&mut self, item: S) {
+        self.item = item.into();
+        self.update(true);
+    }
+
+    pub fn finish(mut self) {
+        self.update(true);
+
+        if self.draw {
+            eprintln!();
+        }
+    }
+
+    /// Counts the bytes read from `inner`.
+    pub fn wrap<R: Read>(self, inner: R) -> Reader<R> {
+        Reader { inner, bar: self }
+    }
+
+    fn update(&mut self, force: bool) {
+        let now = Instant::now();
+
+        if !force
+            && self
+                .last_update
+                .is_some_and(|last| now.duration_since(last) < UPDATE_INTERVAL)
+        {
+            return;
+        }
+
+        self.last_update = Some(now);
+
+        if json() {
+            emit(
+                "progress",
+                json!({
+                    "task": self.task,
+                    "unit": match self.unit {
+                        Unit::Bytes => "bytes",
+                        Unit::Items => "items",
+                    },
+                    "done": self.done,
+                    "total": self.total,
+                }),
+            );
+        } else if self.draw {
+            let filled = (self.done.min(self.total) * BAR_WIDTH)
+                .checked_div(self.total)
+                .unwrap_or(BAR_WIDTH);
+
+            let amount = match self.unit {
+                Unit::Bytes => format!(
+                    "{:.1}/{:.1} MiB",
+                    self.done as f64 / (1024.0 * 1024.0),
+                    self.total as f64 / (1024.0 * 1024.0)
+                ),
+                Unit::Items => format!("{}/{}", self.done, self.total),
+            };
+
+            // Return to the start of the line and clear it.
+            eprint!(
+                "\r\x1b[K{:<32} [{}{}] {} {}",
+                self.task,
+                "#".repeat(filled as usize),
+                " ".repeat((BAR_WIDTH - filled) as usize),
+                amount,
+                self.item
+            );
+            let _ = io::stderr().flush();
+        }
+    }
+}
+
+/// A reader that advances a progress bar.
+#[derive(Debug)]
+pub struct Reader<R> {
+    inner: R,
+    bar: Bar,
+}
+
+impl<R> Reader<R> {
+    pub fn finish(self) {
+        self.bar.finish();
+    }
+}
+
+impl<R: Read> Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bar.inc(n as u64);
+
+        Ok(n)
+    }
+}
@@ -0,0 +1,519 @@
+use crate::cache;
+use crate::config::Config;
+use crate::instance::{self, Instance, Update};
+use crate::prebuilt;
+use crate::progress;
+use crate::squashfs::Superblock;
+use crate::{Image, MiB, RootB};
+
+use anyhow::{anyhow, bail};
+use fscommon::StreamSlice;
+use reqwest::blocking::{Body, Client};
+use std::collections::BTreeMap;
+use std::io::{prelude::*, SeekFrom};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tempfile::NamedTempFile;
+
+const BOOT_START: u64 = 2048 * 512;
+const BOOT_END: u64 = BOOT_START + (256 * MiB) as u64;
+
+/// Size of the bootloader and its parameters at the start of the MBR.
+/// The partition table that follows is left alone.
+const BOOTLOADER_LEN: u64 = 440;
+
+/// How long to wait for the device to go down after requesting a reboot.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Instance file of the device. It is updated after a successful push.
+    instance: String,
+    /// Base URL of the update service. Defaults to the one in the instance file.
+    #[arg(long = "url")]
+    url: Option<String>,
+    /// Token to authenticate with. Defaults to the one in the instance file.
+    #[arg(long = "token")]
+    token: Option<String>,
+    /// Image settings file (JSON). Defaults to the settings in the instance file.
+    #[arg(short = 'f', long = "config")]
+    config: Option<String>,
+    /// Build the latest versions of the recorded crates
+    /// instead of the recorded versions and commits.
+    #[arg(long = "update")]
+    update_crates: bool,
+    /// Seconds to wait for the device to come back after rebooting.
+    #[arg(long = "timeout", default_value_t = 300)]
+    timeout: u64,
+    #[command(flatten)]
+    cache: cache::CacheArgs,
+}
+
+/// An upload and its length in bytes.
+type Part = (Box<dyn Read + Send>, u64);
+
+/// The parts of an update in upload order.
+struct Payload {
+    root: Part,
+    /// The boot partition and the bootloader at the start of the MBR,
+    /// `None` if the boot files didn't change.
+    boot: Option<(Part, Part)>,
+}
+
+/// Client for the update service of a running device.
+#[derive(Debug)]
+pub struct Device {
+    client: Client,
+    update: Update,
+}
+
+impl Device {
+    pub fn new(update: Update) -> anyhow::Result<Self> {
+        // Uploads of root filesystems can take a while on slow links.
+        let client = Client::builder().timeout(None).build()?;
+
+        Ok(Self { client, update })
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.update.url.trim_end_matches('/'), path)
+    }
+
+    /// Uploads `len` bytes from `src` to `path`.
+    pub fn put<R: Read + Send + 'static>(
+        &self,
+        path: &str,
+        src: R,
+        len: u64,
+    ) -> anyhow::Result<()> {
+        self.client
+            .put(self.url(path))
+            .bearer_auth(&self.update.token)
+            .body(Body::sized(src, len))
+            .send()?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    pub fn post(&self, path: &str) -> anyhow::Result<()> {
+        self.client
+            .post(self.url(path))
+            .bearer_auth(&self.update.token)
+            .send()?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    /// Reports whether the update service answers status requests.
+    pub fn is_up(&self) -> bool {
+        self.client
+            .get(self.url("status"))
+            .bearer_auth(&self.update.token)
+            .timeout(Duration::from_secs(5))
+            .send()
+            .and_then(|resp| resp.error_for_status())
+            .is_ok()
+    }
+
+    /// Waits for the device to go down and come back up again.
+    pub fn wait_for_reboot(&self, timeout: Duration) -> anyhow::Result<()> {
+        let start = Instant::now();
+
+        // Don't mistake the old system for the new one.
+        while self.is_up() {
+            if start.elapsed() > SHUTDOWN_TIMEOUT {
+                bail!(
+                    "device didn't go down within {} seconds",
+                    SHUTDOWN_TIMEOUT.as_secs()
+                );
+            }
+
+            thread::sleep(Duration::from_secs(1));
+        }
+
+        while !self.is_up() {
+            if start.elapsed() > timeout {
+                bail!(
+                    "device didn't come back within {} seconds",
+                    timeout.as_secs()
+                );
+            }
+
+            thread::sleep(Duration::from_secs(2));
+        }
+
+        Ok(())
+    }
+}
+
+/// Uploads the update, activates it and waits for the device to come back.
+/// Stops at the first request that fails.
+fn upload(device: &Device, payload: Payload, timeout: Duration) -> anyhow::Result<()> {
+    progress::info("Uploading root filesystem...");
+    device.put("update/root", payload.root.0, payload.root.1)?;
+
+    match payload.boot {
+        Some((boot, bootloader)) => {
+            progress::info("Uploading boot filesystem...");
+            device.put("update/boot", boot.0, boot.1)?;
+
+            progress::info("Uploading bootloader...");
+            device.put("update/mbr", bootloader.0, bootloader.1)?;
+        }
+        None => progress::info("Boot files are unchanged, skipping the boot filesystem"),
+    }
+
+    progress::info("Switching to the inactive root slot...");
+    device.post("update/switch")?;
+
+    progress::info("Rebooting...");
+    device.post("reboot")?;
+
+    device.wait_for_reboot(timeout)?;
+
+    progress::info("Device is back up");
+    Ok(())
+}
+
+pub fn push(args: Args) -> anyhow::Result<()> {
+    let mut info = Instance::load(&args.instance)?;
+
+    let recorded = info.update.clone();
+    let update = Update {
+        url: match args
+            .url
+            .or_else(|| recorded.as_ref().map(|u| u.url.clone()))
+        {
+            Some(url) => url,
+            None => bail!("no update URL, use --url or add it to the instance file"),
+        },
+        token: match args
+            .token
+            .or_else(|| recorded.as_ref().map(|u| u.token.clone()))
+        {
+            Some(token) => token,
+            None => bail!("no update token, use --token or add it to the instance file"),
+        },
+    };
+
+    let (crates, git, init) = info.crate_args(args.update_crates);
+    let init = match init {
+        Some(init) => init,
+        None => bail!("instance file doesn't record an init crate"),
+    };
+
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => info.config.clone().unwrap_or_default(),
+    };
+
+    let image = Image {
+        arch: info.arch.clone(),
+        crates,
+        git,
+        binaries: info
+            .binary_args()
+            .iter()
+            .map(|spec| prebuilt::Binary::parse(spec))
+            .collect::<anyhow::Result<_>>()?,
+        init,
+        config,
+        root_b: RootB::Empty,
+        grow_data: false,
+        update: Some(update.clone()),
+        cache: args.cache.dir()?,
+        kernel_args: None,
+    };
+
+    // The boot partition is built in a scratch disk
+    // so that the bootloader parameters can be computed like for a full image.
+    let mut disk = NamedTempFile::new()?;
+    disk.as_file().set_len(BOOT_END)?;
+
+    let mut boot_partition = StreamSlice::new(disk.reopen()?, BOOT_START, BOOT_END - 1)?;
+
+    let buf = crate::write_boot(
+        &mut boot_partition,
+        &image.arch,
+        image.kernel_args.as_deref(),
+        image.grow_data,
+        true,
+    )?;
+    crate::write_mbr(
+        disk.as_file_mut(),
+        &mut boot_partition,
+        &buf["vmlinuz"],
+        &buf["cmdline.txt"],
+    )?;
+
+    let (mut root, crates, binaries) = crate::build_root(&image)?;
+
+    let root = root.as_file_mut();
+    root.rewind()?;
+    let root_len = Superblock::read(root)?.bytes_used;
+
+    if root_len > (256 * MiB) as u64 {
+        bail!(
+            "root filesystem ({} bytes) doesn't fit into its partition ({} bytes)",
+            root_len,
+            256 * MiB
+        );
+    }
+
+    let boot_files: BTreeMap<_, _> = buf
+        .iter()
+        .map(|(name, contents)| (name.clone(), instance::sha256(contents)))
+        .collect();
+
+    // The bootloader parameters only depend on the boot partition,
+    // so both are skipped if the files in it are the same.
+    let boot = if boot_files == info.boot_files {
+        None
+    } else {
+        let mut boot = disk.reopen()?;
+        boot.seek(SeekFrom::Start(BOOT_START))?;
+
+        Some((
+            (
+                Box::new(boot.take(BOOT_END - BOOT_START)) as Box<dyn Read + Send>,
+                BOOT_END - BOOT_START,
+            ),
+            (
+                Box::new(disk.reopen()?.take(BOOTLOADER_LEN)) as Box<dyn Read + Send>,
+                BOOTLOADER_LEN,
+            ),
+        ))
+    };
+
+    root.rewind()?;
+    let payload = Payload {
+        root: (Box::new(root.try_clone()?.take(root_len)), root_len),
+        boot,
+    };
+
+    let device = Device::new(update.clone())?;
+    upload(&device, payload, Duration::from_secs(args.timeout))
+        .map_err(|e| anyhow!("{}, the instance file is left unchanged", e))?;
+
+    info.schema = instance::SCHEMA;
+    info.packer_version = Some(env!("CARGO_PKG_VERSION").to_owned());
+    info.timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|t| t.as_secs());
+    info.crates = crates;
+    info.binaries = binaries;
+    info.config = Some(image.config);
+    info.boot_files = boot_files;
+    info.update = Some(update);
+
+    info.write(&args.instance)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, BufRead, BufReader, Cursor};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+
+    /// A request received by the mock update service.
+    #[derive(Debug, PartialEq)]
+    struct Request {
+        method: String,
+        path: String,
+        authorization: Option<String>,
+        len: u64,
+    }
+
+    #[derive(Debug, Default)]
+    struct State {
+        requests: Vec<Request>,
+        /// The next status request fails, as if the device was restarting.
+        rebooting: bool,
+    }
+
+    /// Starts an update service that accepts everything except requests to `fail`.
+    fn mock(fail: Option<&'static str>) -> (String, Arc<Mutex<State>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let state = Arc::new(Mutex::new(State::default()));
+        let shared = state.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let state = shared.clone();
+                thread::spawn(move || serve(stream.unwrap(), &state, fail));
+            }
+        });
+
+        (url, state)
+    }
+
+    fn serve(mut stream: TcpStream, state: &Mutex<State>, fail: Option<&str>) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+        // Requests on a kept-alive connection
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+
+            let mut parts = line.split_whitespace();
+            let method = parts.next().unwrap().to_owned();
+            let path = parts.next().unwrap().trim_start_matches('/').to_owned();
+
+            let mut authorization = None;
+            let mut len = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+
+                let header = header.trim_end();
+                if header.is_empty() {
+                    break;
+                }
+
+                let (name, value) = header.split_once(':').unwrap();
+                match name.to_lowercase().as_str() {
+                    "authorization" => authorization = Some(value.trim().to_owned()),
+                    "content-length" => len = value.trim().parse().unwrap(),
+                    _ => {}
+                }
+            }
+
+            let len = io::copy(&mut (&mut reader).take(len), &mut io::sink()).unwrap();
+
+            let status = {
+                let mut state = state.lock().unwrap();
+
+                let status = if Some(path.as_str()) == fail {
+                    500
+                } else if path == "reboot" {
+                    state.rebooting = true;
+                    200
+                } else if path == "status" && state.rebooting {
+                    state.rebooting = false;
+                    503
+                } else {
+                    200
+                };
+
+                state.requests.push(Request {
+                    method,
+                    path,
+                    authorization,
+                    len,
+                });
+
+                status
+            };
+
+            write!(
+                stream,
+                "HTTP/1.1 {} Mock\r\nContent-Length: 0\r\n\r\n",
+                status
+            )
+            .unwrap();
+        }
+    }
+
+    fn device(url: String) -> Device {
+        Device::new(Update {
+            url,
+            token: String::from("secret"),
+        })
+        .unwrap()
+    }
+
+    fn payload() -> Payload {
+        Payload {
+            root: (Box::new(Cursor::new(vec![1; 4096])), 4096),
+            boot: Some((
+                (Box::new(Cursor::new(vec![2; 1024])), 1024),
+                (Box::new(Cursor::new(vec![3; 440])), 440),
+            )),
+        }
+    }
+
+    fn requests(state: &Mutex<State>) -> Vec<(String, String, u64)> {
+        let state = state.lock().unwrap();
+
+        for request in &state.requests {
+            assert_eq!(request.authorization.as_deref(), Some("Bearer secret"));
+        }
+
+        state
+            .requests
+            .iter()
+            .map(|request| (request.method.clone(), request.path.clone(), request.len))
+            .collect()
+    }
+
+    fn expected(requests: &[(&str, &str, u64)]) -> Vec<(String, String, u64)> {
+        requests
+            .iter()
+            .map(|(method, path, len)| (method.to_string(), path.to_string(), *len))
+            .collect()
+    }
+
+    #[test]
+    fn upload_order() {
+        let (url, state) = mock(None);
+
+        upload(&device(url), payload(), Duration::from_secs(10)).unwrap();
+
+        assert_eq!(
+            requests(&state),
+            expected(&[
+                ("PUT", "update/root", 4096),
+                ("PUT", "update/boot", 1024),
+                ("PUT", "update/mbr", 440),
+                ("POST", "update/switch", 0),
+                ("POST", "reboot", 0),
+                ("GET", "status", 0),
+                ("GET", "status", 0),
+            ])
+        );
+    }
+
+    #[test]
+    fn upload_stops_at_first_failure() {
+        let (url, state) = mock(Some("update/boot"));
+
+        assert!(upload(&device(url), payload(), Duration::from_secs(10)).is_err());
+
+        assert_eq!(
+            requests(&state),
+            expected(&[("PUT", "update/root", 4096), ("PUT", "update/boot", 1024)])
+        );
+    }
+
+    #[test]
+    fn upload_skips_unchanged_boot() {
+        let (url, state) = mock(None);
+
+        let payload = Payload {
+            boot: None,
+            ..payload()
+        };
+        upload(&device(url), payload, Duration::from_secs(10)).unwrap();
+
+        assert_eq!(
+            requests(&state),
+            expected(&[
+                ("PUT", "update/root", 4096),
+                ("POST", "update/switch", 0),
+                ("POST", "reboot", 0),
+                ("GET", "status", 0),
+                ("GET", "status", 0),
+            ])
+        );
+    }
+}
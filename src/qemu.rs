@@ -0,0 +1,334 @@
+use crate::instance::Instance;
+use crate::mbr::Mbr;
+use crate::progress;
+use crate::ImageArgs;
+
+use anyhow::{anyhow, bail};
+use std::fs::{self, File, OpenOptions};
+use std::io::{prelude::*, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Device tree of the machine QEMU emulates for rpi.
+const RPI_DTB: &str = "bcm2710-rpi-3-b.dtb";
+
+/// Console messages that end a boot test and the reason they stand for.
+/// The first match wins, so more specific messages come first.
+const FAILURES: [(&str, &str); 3] = [
+    ("No working init found", "missing init"),
+    ("Requested init", "missing init"),
+    ("Kernel panic", "kernel panic"),
+];
+
+#[derive(Debug, clap::Args)]
+pub struct RunArgs {
+    /// Size of the image file in bytes.
+    /// Defaults to the smallest size that fits the contents.
+    #[arg(short = 'n', long = "size")]
+    size: Option<u64>,
+    /// Memory of the virtual machine in MiB. The rpi machine always has 1 GiB.
+    #[arg(long = "memory", default_value_t = 1024)]
+    memory: u32,
+    /// Forward a TCP port from the host to the guest, e.g. 8080:80.
+    #[arg(short = 'p', long = "forward")]
+    forwards: Vec<Forward>,
+    #[command(flatten)]
+    image: ImageArgs,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct TestBootArgs {
+    /// Text the serial console prints once the image has booted successfully.
+    #[arg(short = 's', long = "success")]
+    success: String,
+    /// Seconds to wait for the success text.
+    #[arg(long = "timeout", default_value_t = 120)]
+    timeout: u64,
+    /// Size of the image file in bytes.
+    /// Defaults to the smallest size that fits the contents.
+    #[arg(short = 'n', long = "size")]
+    size: Option<u64>,
+    /// Memory of the virtual machine in MiB. The rpi machine always has 1 GiB.
+    #[arg(long = "memory", default_value_t = 1024)]
+    memory: u32,
+    #[command(flatten)]
+    image: ImageArgs,
+}
+
+/// A TCP port forwarded from the host to the guest.
+#[derive(Clone, Copy, Debug)]
+pub struct Forward {
+    host: u16,
+    guest: u16,
+}
+
+impl FromStr for Forward {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, guest) = s.split_once(':').unwrap_or((s, s));
+
+        Ok(Self {
+            host: host.parse()?,
+            guest: guest.parse()?,
+        })
+    }
+}
+
+/// An image built into a temporary directory for use by QEMU.
+#[derive(Debug)]
+pub struct Vm {
+    dir: TempDir,
+    arch: String,
+    disk: PathBuf,
+}
+
+impl Vm {
+    /// Builds an image file from the command line like a normal build would.
+    pub fn build(args: ImageArgs, size: Option<u64>) -> anyhow::Result<Self> {
+        let from = match &args.from_instance {
+            Some(path) => Some(Instance::load(path)?),
+            None => None,
+        };
+
+        let size = crate::merge_recorded(size, from.as_ref().map(|from| from.size), "size")?;
+        let mut image = crate::resolve_image(args, from.as_ref())?;
+        let arch = image.arch.clone();
+
+        // The bootloader in the MBR passes cmdline.txt to the kernel,
+        // so the serial console is added there before its location is recorded.
+        // For rpi it is added to the command line passed to QEMU instead.
+        if arch == "x86_64" {
+            image.kernel_args = Some(String::from("console=ttyS0,115200"));
+        }
+
+        let size = match size {
+            Some(v) => v,
+            None => crate::auto_size(&image)?,
+        };
+
+        let dir = tempfile::tempdir()?;
+        let disk = dir.path().join("disk.img");
+        let instance = dir.path().join("instance.json");
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&disk)?;
+
+        crate::partition(
+            &mut file,
+            size,
+            instance.to_string_lossy().into_owned(),
+            image,
+            true,
+        )?;
+
+        if arch == "rpi" {
+            // The emulated SD card only accepts sizes that are a power of two.
+            // The extra space is unpartitioned.
+            file.set_len(size.next_power_of_two())?;
+
+            // QEMU can't run the GPU firmware, so the kernel is loaded directly.
+            extract_boot_files(&mut file, dir.path(), &["vmlinuz", "cmdline.txt", RPI_DTB])?;
+        }
+
+        Ok(Self { dir, arch, disk })
+    }
+
+    /// Returns the QEMU command line for the image.
+    /// The caller decides where the serial console goes.
+    pub fn command(&self, memory: u32, forwards: &[Forward]) -> anyhow::Result<Command> {
+        let mut netdev = String::from("user,id=net0");
+        for forward in forwards {
+            netdev += &format!(",hostfwd=tcp::{}-:{}", forward.host, forward.guest);
+        }
+
+        let disk = self.disk.to_string_lossy();
+
+        let cmd = match self.arch.as_str() {
+            "x86_64" => {
+                let mut cmd = Command::new("qemu-system-x86_64");
+
+                // The pc machine boots the MBR using SeaBIOS (legacy BIOS).
+                cmd.args(["-machine", "pc", "-m"])
+                    .arg(memory.to_string())
+                    .arg("-drive")
+                    .arg(format!("file={},format=raw,if=ide", disk))
+                    .arg("-netdev")
+                    .arg(netdev)
+                    .args(["-device", "e1000,netdev=net0"]);
+
+                cmd
+            }
+            "rpi" => {
+                let mut cmdline = fs::read_to_string(self.dir.path().join("cmdline.txt"))?;
+                cmdline = format!("{} console=ttyAMA0,115200", cmdline.trim());
+
+                let mut cmd = Command::new("qemu-system-aarch64");
+
+                cmd.args(["-machine", "raspi3b", "-kernel"])
+                    .arg(self.dir.path().join("vmlinuz"))
+                    .arg("-dtb")
+                    .arg(self.dir.path().join(RPI_DTB))
+                    .arg("-append")
+                    .arg(cmdline)
+                    .arg("-drive")
+                    .arg(format!("file={},format=raw,if=sd", disk))
+                    .arg("-netdev")
+                    .arg(netdev)
+                    .args(["-device", "usb-net,netdev=net0"]);
+
+                cmd
+            }
+            _ => bail!("invalid architecture (supported: x86_64 rpi)"),
+        };
+
+        Ok(cmd)
+    }
+}
+
+/// Copies files from the root directory of the boot partition to `dir`.
+fn extract_boot_files(file: &mut File, dir: &Path, names: &[&str]) -> anyhow::Result<()> {
+    let partition = match Mbr::read(file)?.partitions[0] {
+        Some(partition) => partition,
+        None => bail!("no boot partition"),
+    };
+
+    let mut partition = partition.open(file)?;
+    let fs = fatfs::FileSystem::new(&mut partition, fatfs::FsOptions::new())?;
+    let root_dir = fs.root_dir();
+
+    for name in names {
+        let mut contents = Vec::new();
+        root_dir
+            .open_file(name)
+            .map_err(|e| anyhow!("can't open {} on the boot partition: {}", name, e))?
+            .read_to_end(&mut contents)?;
+
+        fs::write(dir.join(name), contents)?;
+    }
+
+    Ok(())
+}
+
+pub fn run(args: RunArgs) -> anyhow::Result<()> {
+    if progress::json() {
+        bail!("--json can't be used with run, the console is attached to the terminal");
+    }
+
+    let vm = Vm::build(args.image, args.size)?;
+
+    let mut cmd = vm.command(args.memory, &args.forwards)?;
+    cmd.args(["-display", "none", "-serial", "mon:stdio"]);
+
+    progress::info("Starting QEMU, press Ctrl-A X to quit");
+
+    let status = cmd
+        .status()
+        .map_err(|e| anyhow!("can't start {}: {}", cmd.get_program().to_string_lossy(), e))?;
+
+    if !status.success() {
+        bail!("{} failed", cmd.get_program().to_string_lossy());
+    }
+
+    Ok(())
+}
+
+pub fn test_boot(args: TestBootArgs) -> anyhow::Result<()> {
+    let vm = Vm::build(args.image, args.size)?;
+
+    let mut cmd = vm.command(args.memory, &[])?;
+    cmd.args(["-display", "none", "-monitor", "none", "-serial", "stdio"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped());
+
+    progress::info(format!(
+        "Booting image, waiting up to {} seconds...",
+        args.timeout
+    ));
+
+    watch_console(&mut cmd, &args.success, args.timeout)?;
+
+    progress::info("Image booted successfully");
+    Ok(())
+}
+
+/// Runs QEMU until the serial console prints `success`
+/// and fails with the console log if it doesn't within `timeout` seconds.
+fn watch_console(cmd: &mut Command, success: &str, timeout: u64) -> anyhow::Result<()> {
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| anyhow!("can't start {}: {}", cmd.get_program().to_string_lossy(), e))?;
+
+    // Read the console in the background so that the timeout can be enforced.
+    let stdout = child.stdout.take().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdout = BufReader::new(stdout);
+        let mut line = Vec::new();
+
+        while let Ok(n) = stdout.read_until(b'\n', &mut line) {
+            if n == 0 {
+                break;
+            }
+
+            // The receiver is gone once the outcome is known.
+            if tx
+                .send(String::from_utf8_lossy(&line).trim_end().to_owned())
+                .is_err()
+            {
+                break;
+            }
+
+            line.clear();
+        }
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    let mut log = Vec::new();
+
+    let result = loop {
+        match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(line) => {
+                let outcome = if line.contains(success) {
+                    Some(Ok(()))
+                } else {
+                    FAILURES
+                        .iter()
+                        .find(|(message, _)| line.contains(message))
+                        .map(|(_, reason)| Err(anyhow!("{}: {}", reason, line)))
+                };
+
+                log.push(line);
+
+                if let Some(outcome) = outcome {
+                    break outcome;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                break Err(anyhow!(
+                    "timeout: {:?} wasn't printed within {} seconds",
+                    success,
+                    timeout
+                ))
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                break Err(anyhow!("QEMU exited before {:?} was printed", success))
+            }
+        }
+    };
+
+    // QEMU may already have exited.
+    let _ = child.kill();
+    child.wait()?;
+
+    result.map_err(|e| anyhow!("{}\nSerial console log:\n{}", e, log.join("\n")))
+}
@@ -0,0 +1,460 @@
+use crate::caps;
+use crate::config::{Config, DeviceKind};
+
+use anyhow::{anyhow, bail};
+use squashfs_ng::write::{
+    Source as SqsSource, SourceData as SqsSourceData, SourceFile as SqsSourceFile,
+    TreeProcessor as SqsTreeProcessor,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Cursor;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Clone, Debug)]
+pub enum Contents {
+    /// A file on the host.
+    Path(PathBuf),
+    Bytes(Vec<u8>),
+}
+
+impl Contents {
+    pub fn size(&self) -> anyhow::Result<u64> {
+        Ok(match self {
+            Self::Path(src) => fs::metadata(src)
+                .map_err(|e| anyhow!("can't open {}: {}", src.display(), e))?
+                .len(),
+            Self::Bytes(buf) => buf.len() as u64,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Kind {
+    Dir,
+    File(Contents),
+    Symlink(PathBuf),
+    /// Another name for the inode at the given path.
+    Hardlink(PathBuf),
+    CharDev(u32, u32),
+    BlockDev(u32, u32),
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub kind: Kind,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u16,
+    pub xattrs: BTreeMap<OsString, Vec<u8>>,
+}
+
+impl Entry {
+    pub fn dir() -> Self {
+        Self::new(Kind::Dir, 0o755)
+    }
+
+    /// A regular file with mode 0644, see [`Entry::executable`] for programs.
+    pub fn file(contents: Contents) -> Self {
+        Self::new(Kind::File(contents), 0o644)
+    }
+
+    /// A regular file with mode 0755.
+    pub fn executable(contents: Contents) -> Self {
+        Self::new(Kind::File(contents), 0o755)
+    }
+
+    pub fn symlink<P: Into<PathBuf>>(target: P) -> Self {
+        Self::new(Kind::Symlink(target.into()), 0o777)
+    }
+
+    pub fn hardlink<P: Into<PathBuf>>(target: P) -> Self {
+        Self::new(Kind::Hardlink(target.into()), 0)
+    }
+
+    pub fn with_mode(mut self, mode: u16) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    fn new(kind: Kind, mode: u16) -> Self {
+        Self {
+            kind,
+            uid: 0,
+            gid: 0,
+            mode,
+            xattrs: BTreeMap::new(),
+        }
+    }
+}
+
+/// In-memory description of a root filesystem.
+#[derive(Clone, Debug)]
+pub struct Rootfs {
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+impl Default for Rootfs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rootfs {
+    /// Creates the directory skeleton every rustkrazy root filesystem has.
+    pub fn new() -> Self {
+        let mut entries = BTreeMap::new();
+        entries.insert(PathBuf::from("/"), Entry::dir());
+
+        for dir in ["bin", "dev", "boot", "data", "proc", "tmp", "run"] {
+            entries.insert(Path::new("/").join(dir), Entry::dir());
+        }
+
+        Self { entries }
+    }
+
+    /// Adds an entry, creating missing parent directories.
+    /// Directories may be inserted again to change their metadata.
+    pub fn insert<P: AsRef<Path>>(&mut self, path: P, entry: Entry) -> anyhow::Result<()> {
+        let path = normalize(path.as_ref())?;
+
+        for parent in path.ancestors().skip(1) {
+            match self.entries.get(parent) {
+                Some(Entry {
+                    kind: Kind::Dir, ..
+                }) => break,
+                Some(_) => bail!(
+                    "can't create {}: {} is not a directory",
+                    path.display(),
+                    parent.display()
+                ),
+                None => {
+                    self.entries.insert(parent.to_path_buf(), Entry::dir());
+                }
+            }
+        }
+
+        match (self.entries.get(&path), &entry.kind) {
+            (None, _) => {}
+            (
+                Some(Entry {
+                    kind: Kind::Dir, ..
+                }),
+                Kind::Dir,
+            ) => {}
+            (Some(_), _) => bail!("duplicate root filesystem entry: {}", path.display()),
+        }
+
+        self.entries.insert(path, entry);
+        Ok(())
+    }
+
+    /// Adds the symlinks, hardlinks and device nodes requested in the image settings.
+    pub fn add_special(&mut self, config: &Config) -> anyhow::Result<()> {
+        for (path, target) in &config.symlinks {
+            self.insert(path, Entry::symlink(target))?;
+        }
+
+        for (path, target) in &config.hardlinks {
+            self.insert(path, Entry::hardlink(normalize(Path::new(target))?))?;
+        }
+
+        for device in &config.devices {
+            let kind = match device.kind {
+                DeviceKind::Char => Kind::CharDev(device.major, device.minor),
+                DeviceKind::Block => Kind::BlockDev(device.major, device.minor),
+            };
+
+            self.insert(&device.path, Entry::new(kind, device.mode))?;
+        }
+
+        Ok(())
+    }
+
+    /// Copies the contents of a host directory into the root filesystem.
+    pub fn add_overlay<P: AsRef<Path>>(&mut self, dir: P) -> anyhow::Result<()> {
+        self.add_overlay_dir(dir.as_ref(), Path::new("/"))
+    }
+
+    fn add_overlay_dir(&mut self, src: &Path, dst: &Path) -> anyhow::Result<()> {
+        for dir_entry in fs::read_dir(src)? {
+            let dir_entry = dir_entry?;
+            let src = dir_entry.path();
+            let dst = dst.join(dir_entry.file_name());
+
+            let metadata = fs::symlink_metadata(&src)?;
+            let mode = (metadata.permissions().mode() & 0o7777) as u16;
+
+            if metadata.is_dir() {
+                self.insert(&dst, Entry::dir().with_mode(mode))?;
+                self.add_overlay_dir(&src, &dst)?;
+            } else if metadata.is_symlink() {
+                self.insert(&dst, Entry::symlink(fs::read_link(&src)?))?;
+            } else if metadata.is_file() {
+                self.insert(&dst, Entry::file(Contents::Path(src)).with_mode(mode))?;
+            } else {
+                bail!("unsupported file type in overlay: {}", src.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Installs the files and applies the ownership, modes, xattrs and capabilities
+    /// requested in the image settings.
+    pub fn add_files(&mut self, config: &Config) -> anyhow::Result<()> {
+        for (path, attrs) in &config.files {
+            if let Some(src) = &attrs.source {
+                self.insert(path, Entry::file(Contents::Path(PathBuf::from(src))))?;
+            }
+
+            let entry = match self.entries.get_mut(&normalize(Path::new(path))?) {
+                Some(Entry {
+                    kind: Kind::Hardlink(target),
+                    ..
+                }) => bail!(
+                    "can't set attributes of hardlink {}, use {} instead",
+                    path,
+                    target.display()
+                ),
+                Some(entry) => entry,
+                None => bail!("can't set attributes of {}: no such file", path),
+            };
+
+            if let Some(uid) = attrs.uid {
+                entry.uid = uid;
+            }
+
+            if let Some(gid) = attrs.gid {
+                entry.gid = gid;
+            }
+
+            if let Some(mode) = attrs.mode {
+                entry.mode = mode;
+            }
+
+            for (name, value) in &attrs.xattrs {
+                if !["user.", "trusted.", "security."]
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix))
+                {
+                    bail!("unsupported xattr namespace: {}", name);
+                }
+
+                entry
+                    .xattrs
+                    .insert(OsString::from(name), xattr_value(value)?);
+            }
+
+            if !attrs.capabilities.is_empty() {
+                entry.xattrs.insert(
+                    OsString::from(caps::XATTR_NAME),
+                    caps::encode(&attrs.capabilities)?,
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns all entries sorted by path.
+    pub fn entries(&self) -> impl Iterator<Item = (&Path, &Entry)> {
+        self.entries
+            .iter()
+            .map(|(path, entry)| (path.as_path(), entry))
+    }
+
+    /// Returns the total size of all regular files.
+    pub fn data_size(&self) -> anyhow::Result<u64> {
+        let mut size = 0;
+
+        for entry in self.entries.values() {
+            if let Kind::File(contents) = &entry.kind {
+                size += contents.size()?;
+            }
+        }
+
+        Ok(size)
+    }
+
+    /// Follows a hardlink, and any hardlinks it points to, to the file they all name.
+    pub fn hardlink_target<'a>(&'a self, path: &'a Path) -> anyhow::Result<&'a Path> {
+        let mut target = path;
+
+        // Every step visits a different entry unless the chain is a cycle.
+        for _ in 0..self.entries.len() {
+            match self.entries.get(target).map(|entry| &entry.kind) {
+                Some(Kind::Hardlink(next)) => target = next,
+                Some(Kind::Dir) | None => bail!(
+                    "hardlink {} points to {} which is missing or a directory",
+                    path.display(),
+                    target.display()
+                ),
+                Some(_) => return Ok(target),
+            }
+        }
+
+        bail!("hardlink {} is part of a cycle", path.display())
+    }
+
+    /// Adds all entries to the squashfs tree. Children are added before their parents.
+    pub fn write(&self, tree: &SqsTreeProcessor) -> anyhow::Result<()> {
+        let mut inodes = HashMap::new();
+
+        for (path, entry) in &self.entries {
+            let data = match &entry.kind {
+                Kind::Dir | Kind::Hardlink(_) => continue,
+                Kind::File(Contents::Path(src)) => SqsSourceData::File(Box::new(
+                    File::open(src).map_err(|e| anyhow!("can't open {}: {}", src.display(), e))?,
+                )),
+                Kind::File(Contents::Bytes(buf)) => {
+                    SqsSourceData::File(Box::new(Cursor::new(buf.clone())))
+                }
+                Kind::Symlink(target) => SqsSourceData::Symlink(target.clone()),
+                Kind::CharDev(major, minor) => SqsSourceData::CharDev(*major, *minor),
+                Kind::BlockDev(major, minor) => SqsSourceData::BlockDev(*major, *minor),
+            };
+
+            let inode = tree.add(SqsSourceFile {
+                path: path.clone(),
+                content: source(entry, data),
+            })?;
+
+            inodes.insert(path.as_path(), inode);
+        }
+
+        for (path, entry) in &self.entries {
+            if let Kind::Hardlink(_) = &entry.kind {
+                let inode = inodes[self.hardlink_target(path)?];
+                inodes.insert(path.as_path(), inode);
+            }
+        }
+
+        let mut dirs: Vec<&Path> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| matches!(entry.kind, Kind::Dir))
+            .map(|(path, _)| path.as_path())
+            .collect();
+
+        // Deepest directories first so that child inodes are known.
+        dirs.sort_by_key(|path| std::cmp::Reverse(path.components().count()));
+
+        for path in dirs {
+            let children: Vec<(OsString, u32)> = self
+                .entries
+                .keys()
+                .filter(|child| child.parent() == Some(path))
+                .map(|child| {
+                    (
+                        child.file_name().unwrap().to_owned(),
+                        inodes[child.as_path()],
+                    )
+                })
+                .collect();
+
+            let inode = tree.add(SqsSourceFile {
+                path: path.to_path_buf(),
+                content: source(
+                    &self.entries[path],
+                    SqsSourceData::Dir(Box::new(children.into_iter())),
+                ),
+            })?;
+
+            inodes.insert(path, inode);
+        }
+
+        Ok(())
+    }
+}
+
+fn source(entry: &Entry, data: SqsSourceData) -> SqsSource {
+    SqsSource {
+        data,
+        uid: entry.uid,
+        gid: entry.gid,
+        mode: entry.mode,
+        modified: 0,
+        xattrs: entry.xattrs.clone().into_iter().collect(),
+        flags: 0,
+    }
+}
+
+/// Decodes an xattr value from the image settings.
+/// Values prefixed with `hex:` are hex encoded, everything else is stored as is.
+fn xattr_value(value: &str) -> anyhow::Result<Vec<u8>> {
+    let hex = match value.strip_prefix("hex:") {
+        Some(hex) => hex,
+        None => return Ok(value.as_bytes().to_vec()),
+    };
+
+    if hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("invalid hex xattr value: {}", value);
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?))
+        .collect()
+}
+
+/// Checks that the path is absolute and free of `.` and `..`.
+fn normalize(path: &Path) -> anyhow::Result<PathBuf> {
+    let mut normalized = PathBuf::from("/");
+
+    for (i, component) in path.components().enumerate() {
+        match component {
+            Component::RootDir if i == 0 => {}
+            Component::Normal(name) if i > 0 => normalized.push(name),
+            _ => bail!("invalid root filesystem path: {}", path.display()),
+        }
+    }
+
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hardlink_chains() {
+        let mut rootfs = Rootfs::new();
+        rootfs
+            .insert("/bin/app", Entry::executable(Contents::Bytes(Vec::new())))
+            .unwrap();
+        // Sorted before the link it points to.
+        rootfs.insert("/bin/a", Entry::hardlink("/bin/z")).unwrap();
+        rootfs
+            .insert("/bin/z", Entry::hardlink("/bin/app"))
+            .unwrap();
+        rootfs.insert("/bin/dir", Entry::hardlink("/bin")).unwrap();
+        rootfs.insert("/bin/x", Entry::hardlink("/bin/y")).unwrap();
+        rootfs.insert("/bin/y", Entry::hardlink("/bin/x")).unwrap();
+
+        let target = |path: &str| {
+            rootfs
+                .hardlink_target(Path::new(path))
+                .map(Path::to_path_buf)
+        };
+
+        assert_eq!(target("/bin/a").unwrap(), Path::new("/bin/app"));
+        assert_eq!(target("/bin/z").unwrap(), Path::new("/bin/app"));
+        assert!(target("/bin/dir").is_err());
+        assert!(target("/bin/x").is_err());
+    }
+
+    #[test]
+    fn xattr_values() {
+        assert_eq!(xattr_value("vault").unwrap(), b"vault");
+        assert_eq!(xattr_value("0x00ff").unwrap(), b"0x00ff");
+        assert_eq!(xattr_value("hex:00ff").unwrap(), [0x00, 0xff]);
+        assert_eq!(xattr_value("hex:").unwrap(), b"");
+
+        for invalid in ["hex:0", "hex:zz", "hex:+f", "hex:\u{e9}"] {
+            assert!(xattr_value(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::io::{self, prelude::*, SeekFrom};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Copies `src` to `dst`. If `sparse` is set `dst` is known to read as zeros,
+/// so chunks that are entirely zero are skipped, leaving holes in image files.
+pub fn copy<R: Read, W: Write + Seek>(src: &mut R, dst: &mut W, sparse: bool) -> io::Result<u64> {
+    if !sparse {
+        return io::copy(src, dst);
+    }
+
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut total = 0;
+
+    loop {
+        let mut n = 0;
+        while n < buf.len() {
+            match src.read(&mut buf[n..]) {
+                Ok(0) => break,
+                Ok(m) => n += m,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        if n == 0 {
+            break;
+        }
+
+        if buf[..n].iter().all(|b| *b == 0) {
+            dst.seek(SeekFrom::Current(n as i64))?;
+        } else {
+            dst.write_all(&buf[..n])?;
+        }
+
+        total += n as u64;
+    }
+
+    Ok(total)
+}
+
+/// Writes `len` zero bytes to `dst` unless it is known to read as zeros already.
+pub fn zero<W: Write + Seek>(dst: &mut W, len: u64, sparse: bool) -> io::Result<()> {
+    if sparse {
+        dst.seek(SeekFrom::Current(len as i64))?;
+    } else {
+        io::copy(&mut io::repeat(0).take(len), dst)?;
+    }
+
+    Ok(())
+}
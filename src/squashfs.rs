@@ -0,0 +1,590 @@
+use crate::config::{Compression, Compressor};
+use crate::progress;
+use crate::rootfs::{Contents, Kind, Rootfs};
+
+use anyhow::{anyhow, bail};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::Stdio;
+use tempfile::NamedTempFile;
+
+const MAGIC: u32 = 0x73717368; // hsqs
+
+/// The parts of a squashfs superblock the packer needs.
+#[derive(Clone, Debug)]
+pub struct Superblock {
+    /// Size of the filesystem without padding.
+    pub bytes_used: u64,
+}
+
+impl Superblock {
+    pub fn read<R: Read>(r: &mut R) -> anyhow::Result<Self> {
+        let mut buf = [0; 96];
+        r.read_exact(&mut buf)?;
+
+        if u32::from_le_bytes(buf[0..4].try_into()?) != MAGIC {
+            bail!("not a squashfs filesystem");
+        }
+
+        Ok(Self {
+            bytes_used: u64::from_le_bytes(buf[40..48].try_into()?),
+        })
+    }
+}
+
+impl Compressor {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Lz4 => "lz4",
+            Self::Lzo => "lzo",
+            Self::Xz => "xz",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    fn max_level(&self) -> Option<u32> {
+        match self {
+            Self::Gzip => Some(9),
+            Self::Lz4 => None,
+            Self::Lzo => Some(9),
+            Self::Xz => Some(9),
+            Self::Zstd => Some(22),
+        }
+    }
+}
+
+/// Writes `rootfs` to a squashfs at `dst` using the requested compression settings.
+/// This streams a tar archive into tar2sqfs from squashfs-tools-ng
+/// because the squashfs library always uses its defaults.
+pub fn create(rootfs: &Rootfs, dst: &Path, compression: &Compression) -> anyhow::Result<()> {
+    let compressor = compression.compressor;
+
+    // The root directory isn't part of the archive.
+    let root = rootfs
+        .entries()
+        .next()
+        .map(|(_, entry)| entry)
+        .ok_or_else(|| anyhow!("empty root filesystem"))?;
+
+    let mut tar2sqfs = crate::no_stdin("tar2sqfs");
+    tar2sqfs
+        .arg("--quiet")
+        .arg("--force")
+        .arg("--no-skip")
+        .arg("--defaults")
+        .arg(format!(
+            "uid={},gid={},mode={:04o}",
+            root.uid, root.gid, root.mode
+        ))
+        .arg("--compressor")
+        .arg(compressor.as_str());
+
+    if let Some(level) = compression.level {
+        match compressor.max_level() {
+            Some(max) if (1..=max).contains(&level) => {
+                tar2sqfs.arg("--comp-extra").arg(format!("level={}", level));
+            }
+            Some(max) => bail!(
+                "{} compression level must be between 1 and {}",
+                compressor.as_str(),
+                max
+            ),
+            None => bail!("{} has no compression levels", compressor.as_str()),
+        }
+    }
+
+    if let Some(block_size) = compression.block_size {
+        if !block_size.is_power_of_two() || !(4096..=1048576).contains(&block_size) {
+            bail!("block size must be a power of two between 4096 and 1048576");
+        }
+
+        tar2sqfs.arg("--block-size").arg(block_size.to_string());
+    }
+
+    tar2sqfs.arg(dst).stdin(Stdio::piped());
+
+    let mut tar2sqfs = tar2sqfs.spawn()?;
+
+    let mut stdin = BufWriter::new(tar2sqfs.stdin.take().unwrap());
+    let written = write_tar(rootfs, &mut stdin).and_then(|_| Ok(stdin.flush()?));
+    drop(stdin);
+
+    // A failing tar2sqfs closes the pipe, its exit status is the more useful error.
+    if !tar2sqfs.wait()?.success() {
+        bail!("tar2sqfs failed");
+    }
+
+    written
+}
+
+/// Writes all entries except the root directory as a pax archive.
+/// Hardlinks come last so that their targets are known to the reader
+/// and point to the file directly, even if configured as a chain.
+fn write_tar<W: Write>(rootfs: &Rootfs, w: &mut W) -> anyhow::Result<()> {
+    let entries = rootfs.entries().skip(1);
+    let (hardlinks, others): (Vec<_>, Vec<_>) =
+        entries.partition(|(_, entry)| matches!(entry.kind, Kind::Hardlink(_)));
+
+    for (path, entry) in others.into_iter().chain(hardlinks) {
+        let (kind, size, target, device) = match &entry.kind {
+            Kind::Dir => (b'5', 0, None, (0, 0)),
+            Kind::File(contents) => (b'0', contents.size()?, None, (0, 0)),
+            Kind::Symlink(target) => (b'2', 0, Some(target.as_path()), (0, 0)),
+            Kind::Hardlink(_) => (b'1', 0, Some(rootfs.hardlink_target(path)?), (0, 0)),
+            Kind::CharDev(major, minor) => (b'3', 0, None, (*major, *minor)),
+            Kind::BlockDev(major, minor) => (b'4', 0, None, (*major, *minor)),
+        };
+
+        let path = path.strip_prefix("/").unwrap_or(path);
+        let target = target.map(|target| match entry.kind {
+            Kind::Hardlink(_) => target.strip_prefix("/").unwrap_or(target),
+            _ => target,
+        });
+
+        let mut pax = Vec::new();
+        let mut header = [0; 512];
+
+        let name = path.as_os_str().as_bytes();
+        if name.len() > 100 {
+            pax.extend(pax_record("path", name));
+        } else {
+            header[0..name.len()].copy_from_slice(name);
+        }
+
+        if let Some(target) = target {
+            let target = target.as_os_str().as_bytes();
+            if target.len() > 100 {
+                pax.extend(pax_record("linkpath", target));
+            } else {
+                header[157..157 + target.len()].copy_from_slice(target);
+            }
+        }
+
+        for (field, range, value) in [
+            ("uid", 108..116, entry.uid as u64),
+            ("gid", 116..124, entry.gid as u64),
+            ("size", 124..136, size),
+        ] {
+            if !put_octal(&mut header[range], value) {
+                pax.extend(pax_record(field, value.to_string().as_bytes()));
+            }
+        }
+
+        for (name, value) in &entry.xattrs {
+            let key = format!("SCHILY.xattr.{}", name.to_string_lossy());
+            pax.extend(pax_record(&key, value));
+        }
+
+        put_octal(&mut header[100..108], entry.mode as u64);
+        put_octal(&mut header[136..148], 0);
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        put_octal(&mut header[329..337], device.0 as u64);
+        put_octal(&mut header[337..345], device.1 as u64);
+
+        if !pax.is_empty() {
+            let mut pax_header = [0; 512];
+            pax_header[0..9].copy_from_slice(b"PaxHeader");
+            put_octal(&mut pax_header[100..108], 0o644);
+            put_octal(&mut pax_header[124..136], pax.len() as u64);
+            put_octal(&mut pax_header[136..148], 0);
+            pax_header[156] = b'x';
+            pax_header[257..263].copy_from_slice(b"ustar\0");
+            pax_header[263..265].copy_from_slice(b"00");
+
+            write_block(w, pax_header)?;
+            w.write_all(&pax)?;
+            pad(w, pax.len() as u64)?;
+        }
+
+        write_block(w, header)?;
+
+        match &entry.kind {
+            Kind::File(Contents::Path(src)) => {
+                let file =
+                    File::open(src).map_err(|e| anyhow!("can't open {}: {}", src.display(), e))?;
+
+                if io::copy(&mut file.take(size), w)? != size {
+                    bail!("{} changed while it was being added", src.display());
+                }
+
+                pad(w, size)?;
+            }
+            Kind::File(Contents::Bytes(buf)) => {
+                w.write_all(buf)?;
+                pad(w, size)?;
+            }
+            _ => {}
+        }
+    }
+
+    // End of archive
+    w.write_all(&[0; 1024])?;
+
+    Ok(())
+}
+
+/// Fills in the checksum and writes a header block.
+fn write_block<W: Write>(w: &mut W, mut header: [u8; 512]) -> anyhow::Result<()> {
+    header[148..156].fill(b' ');
+    let checksum: u64 = header.iter().map(|b| *b as u64).sum();
+    put_octal(&mut header[148..155], checksum);
+
+    w.write_all(&header)?;
+    Ok(())
+}
+
+/// Pads data of `len` bytes to a multiple of the block size.
+fn pad<W: Write>(w: &mut W, len: u64) -> anyhow::Result<()> {
+    let padding = len.div_ceil(512) * 512 - len;
+    w.write_all(&vec![0; padding as usize])?;
+
+    Ok(())
+}
+
+/// Writes `value` as a zero-terminated octal number filling `field`.
+/// Returns false if it doesn't fit.
+fn put_octal(field: &mut [u8], value: u64) -> bool {
+    let digits = field.len() - 1;
+    let s = format!("{:0width$o}", value, width = digits);
+
+    if s.len() > digits {
+        return false;
+    }
+
+    field[..digits].copy_from_slice(s.as_bytes());
+    field[digits] = 0;
+    true
+}
+
+/// Returns a pax record ("<len> <key>=<value>\n"). The length includes itself.
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+
+    let mut record = format!("{} {}=", len, key).into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+/// Prints how well the root filesystem compressed.
+pub fn report_ratio(data_size: u64, bytes_used: u64) {
+    progress::info(format!(
+        "Root filesystem: {} bytes of files stored in {} bytes ({:.1}%)",
+        data_size,
+        bytes_used,
+        if data_size == 0 {
+            100.0
+        } else {
+            bytes_used as f64 * 100.0 / data_size as f64
+        }
+    ));
+}
+
+/// Copies the squashfs at the start of `partition` into a temporary file
+/// because the squashfs tools can't read from an offset.
+pub fn extract<R: Read + Seek>(partition: &mut R) -> anyhow::Result<NamedTempFile> {
+    partition.rewind()?;
+    let superblock = Superblock::read(partition)?;
+
+    let mut tmp_file = NamedTempFile::new()?;
+
+    partition.rewind()?;
+    io::copy(&mut partition.take(superblock.bytes_used), &mut tmp_file)?;
+
+    Ok(tmp_file)
+}
+
+/// An entry of a squashfs image.
+#[derive(Clone, Debug, Serialize)]
+pub struct Node {
+    pub path: String,
+    #[serde(rename = "type")]
+    pub kind: NodeKind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Size of the file contents, zero for anything else.
+    pub size: u64,
+    /// Target of symlinks and hardlinks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeKind {
+    File,
+    Hardlink,
+    Symlink,
+    Dir,
+    Char,
+    Block,
+    Fifo,
+}
+
+/// Lists the contents of the squashfs at `path`.
+/// This reads the tar stream produced by sqfs2tar from squashfs-tools-ng.
+pub fn list(path: &Path) -> anyhow::Result<Vec<Node>> {
+    let mut sqfs2tar = crate::no_stdin("sqfs2tar");
+    sqfs2tar.arg("--no-xattr").arg(path).stdout(Stdio::piped());
+
+    let mut sqfs2tar = sqfs2tar.spawn()?;
+    let nodes = read_tar(&mut BufReader::new(sqfs2tar.stdout.take().unwrap()));
+
+    if !sqfs2tar.wait()?.success() {
+        bail!("sqfs2tar failed");
+    }
+
+    nodes
+}
+
+/// Returns the contents of the file at `path` in the squashfs `image`.
+/// This uses rdsquashfs from squashfs-tools-ng.
+pub fn cat(image: &Path, path: &str) -> anyhow::Result<Vec<u8>> {
+    let output = crate::no_stdin("rdsquashfs")
+        .arg("--cat")
+        .arg(path)
+        .arg(image)
+        .output()?;
+
+    if !output.status.success() {
+        bail!(
+            "rdsquashfs failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(output.stdout)
+}
+
+fn read_tar<R: Read>(r: &mut R) -> anyhow::Result<Vec<Node>> {
+    let mut nodes = Vec::new();
+
+    // Extended attributes of the next entry.
+    let mut pax = BTreeMap::new();
+
+    let mut header = [0; 512];
+    loop {
+        r.read_exact(&mut header)?;
+
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+
+        let size = octal(&header[124..136])?;
+        let padded_size = size.div_ceil(512) * 512;
+
+        match header[156] {
+            // pax extended header, records have the format "<len> <key>=<value>\n"
+            b'x' => {
+                let mut data = vec![0; padded_size as usize];
+                r.read_exact(&mut data)?;
+                data.truncate(size as usize);
+
+                for record in String::from_utf8_lossy(&data).lines() {
+                    if let Some((key, value)) = record
+                        .split_once(' ')
+                        .and_then(|(_, record)| record.split_once('='))
+                    {
+                        pax.insert(key.to_owned(), value.to_owned());
+                    }
+                }
+
+                continue;
+            }
+            // GNU long name
+            b'L' => {
+                let mut data = vec![0; padded_size as usize];
+                r.read_exact(&mut data)?;
+
+                pax.insert(String::from("path"), c_str(&data[..size as usize]));
+                continue;
+            }
+            b'g' => {
+                io::copy(&mut r.take(padded_size), &mut io::sink())?;
+                continue;
+            }
+            _ => {}
+        }
+
+        let kind = match header[156] {
+            b'0' | 0 => NodeKind::File,
+            b'1' => NodeKind::Hardlink,
+            b'2' => NodeKind::Symlink,
+            b'3' => NodeKind::Char,
+            b'4' => NodeKind::Block,
+            b'5' => NodeKind::Dir,
+            b'6' => NodeKind::Fifo,
+            kind => bail!("unsupported tar entry type {}", kind as char),
+        };
+
+        let path = match pax.remove("path") {
+            Some(path) => path,
+            None => {
+                let name = c_str(&header[0..100]);
+                let prefix = c_str(&header[345..500]);
+
+                if prefix.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", prefix, name)
+                }
+            }
+        };
+
+        let size = match pax.remove("size") {
+            Some(size) => size.parse()?,
+            None => size,
+        };
+
+        let target = match kind {
+            NodeKind::Hardlink | NodeKind::Symlink => Some(
+                pax.remove("linkpath")
+                    .unwrap_or_else(|| c_str(&header[157..257])),
+            ),
+            _ => None,
+        };
+
+        let uid = match pax.remove("uid") {
+            Some(uid) => uid.parse()?,
+            None => octal(&header[108..116])? as u32,
+        };
+        let gid = match pax.remove("gid") {
+            Some(gid) => gid.parse()?,
+            None => octal(&header[116..124])? as u32,
+        };
+
+        pax.clear();
+
+        if kind == NodeKind::File {
+            io::copy(&mut r.take(size.div_ceil(512) * 512), &mut io::sink())?;
+        }
+
+        nodes.push(Node {
+            path: format!("/{}", path.trim_start_matches("./").trim_matches('/')),
+            kind,
+            mode: octal(&header[100..108])? as u32 & 0o7777,
+            uid,
+            gid,
+            size: if kind == NodeKind::File { size } else { 0 },
+            target: target.map(|target| match kind {
+                NodeKind::Hardlink => format!("/{}", target.trim_start_matches('/')),
+                _ => target,
+            }),
+        });
+    }
+
+    Ok(nodes)
+}
+
+fn octal(field: &[u8]) -> anyhow::Result<u64> {
+    let s = c_str(field);
+    let s = s.trim();
+
+    if s.is_empty() {
+        return Ok(0);
+    }
+
+    Ok(u64::from_str_radix(s, 8)?)
+}
+
+fn c_str(buf: &[u8]) -> String {
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rootfs::Entry;
+    use std::ffi::OsString;
+
+    #[test]
+    fn tar_round_trip() {
+        let long = format!("/data/{}/file", "x".repeat(120));
+
+        let mut rootfs = Rootfs::new();
+        rootfs
+            .insert(
+                "/bin/app",
+                Entry::executable(Contents::Bytes(vec![1; 1000])),
+            )
+            .unwrap();
+        rootfs
+            .insert(&long, Entry::file(Contents::Bytes(b"hello".to_vec())))
+            .unwrap();
+        rootfs.insert("/bin/sh", Entry::symlink("app")).unwrap();
+        rootfs
+            .insert("/bin/app2", Entry::hardlink("/bin/app"))
+            .unwrap();
+        rootfs
+            .insert("/bin/app1", Entry::hardlink("/bin/app2"))
+            .unwrap();
+
+        let mut owned = Entry::file(Contents::Bytes(Vec::new()));
+        owned.uid = 1000;
+        owned.gid = 4_000_000;
+        owned
+            .xattrs
+            .insert(OsString::from("user.comment"), b"a=b\n".to_vec());
+        rootfs.insert("/etc/owned", owned).unwrap();
+
+        let mut null = Entry::file(Contents::Bytes(Vec::new())).with_mode(0o666);
+        null.kind = Kind::CharDev(1, 3);
+        rootfs.insert("/dev/null", null).unwrap();
+
+        let mut tar = Vec::new();
+        write_tar(&rootfs, &mut tar).unwrap();
+        assert_eq!(tar.len() % 512, 0);
+
+        let nodes = read_tar(&mut tar.as_slice()).unwrap();
+        let node = |path: &str| nodes.iter().find(|node| node.path == path).unwrap();
+
+        // Everything but the root directory, hardlinks last
+        assert_eq!(nodes.len(), rootfs.entries().count() - 1);
+        assert_eq!(nodes.last().unwrap().path, "/bin/app2");
+
+        let app = node("/bin/app");
+        assert_eq!(
+            (app.kind, app.mode, app.size),
+            (NodeKind::File, 0o755, 1000)
+        );
+
+        let file = node(&long);
+        assert_eq!((file.mode, file.size), (0o644, 5));
+        assert_eq!(node("/bin/sh").target.as_deref(), Some("app"));
+
+        for path in ["/bin/app1", "/bin/app2"] {
+            let link = node(path);
+            assert_eq!(link.kind, NodeKind::Hardlink);
+            assert_eq!(link.target.as_deref(), Some("/bin/app"));
+        }
+
+        let owned = node("/etc/owned");
+        assert_eq!((owned.uid, owned.gid), (1000, 4_000_000));
+
+        assert_eq!(node("/dev/null").kind, NodeKind::Char);
+        assert_eq!(node("/bin").kind, NodeKind::Dir);
+    }
+
+    #[test]
+    fn pax_record_length() {
+        assert_eq!(pax_record("path", b"a"), b"9 path=a\n");
+        // The length field grows from one to two digits.
+        let record = pax_record("path", b"abc");
+        assert_eq!(record, b"12 path=abc\n");
+        assert_eq!(record.len(), 12);
+    }
+}
@@ -0,0 +1,219 @@
+use crate::config::{Preset, Toolchain};
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// Compiler settings for the crates of an image.
+#[derive(Debug)]
+pub struct Resolved {
+    pub rustc_args: Vec<String>,
+    /// Environment variables for the build scripts of sys crates,
+    /// e.g. CC_aarch64_unknown_linux_musl.
+    pub env: Vec<(String, String)>,
+}
+
+/// Programs and flags of a toolchain before they are turned into rustc arguments
+/// and environment variables.
+#[derive(Debug, Default)]
+struct Tools {
+    linker: Option<String>,
+    cc: Option<String>,
+    cxx: Option<String>,
+    ar: Option<String>,
+    cflags: Vec<String>,
+    link_args: Vec<String>,
+}
+
+/// The toolchain used if the config doesn't have one for `arch`.
+/// Only rpi needs a cross linker, C compilers are left to the cc crate.
+pub fn default_for(arch: &str) -> Toolchain {
+    match arch {
+        "rpi" => Toolchain {
+            linker: Some(String::from("aarch64-linux-gnu-ld")),
+            ..Default::default()
+        },
+        _ => Toolchain::default(),
+    }
+}
+
+/// Applies the preset and explicit settings of `toolchain` for a target triple.
+/// `cpu` is its first component, e.g. aarch64.
+/// Wrapper scripts for compilers that need arguments (e.g. zig cc) are written to `wrappers`.
+pub fn resolve(
+    toolchain: &Toolchain,
+    cpu: &str,
+    triple: &str,
+    wrappers: &Path,
+) -> anyhow::Result<Resolved> {
+    let mut tools = match toolchain.preset {
+        Some(preset) => self::preset(preset, cpu, wrappers)?,
+        None => Tools::default(),
+    };
+
+    if let Some(linker) = &toolchain.linker {
+        tools.linker = Some(linker.clone());
+    }
+    if let Some(cc) = &toolchain.cc {
+        tools.cc = Some(cc.clone());
+    }
+    if let Some(cxx) = &toolchain.cxx {
+        tools.cxx = Some(cxx.clone());
+    }
+    if let Some(ar) = &toolchain.ar {
+        tools.ar = Some(ar.clone());
+    }
+    if let Some(sysroot) = &toolchain.sysroot {
+        tools.cflags.push(format!("--sysroot={}", sysroot));
+        tools.link_args.push(format!("--sysroot={}", sysroot));
+    }
+
+    let mut rustc_args = Vec::new();
+
+    if let Some(linker) = tools.linker {
+        rustc_args.push(String::from("-C"));
+        rustc_args.push(format!("linker={}", linker));
+    }
+
+    for arg in tools.link_args {
+        rustc_args.push(String::from("-C"));
+        rustc_args.push(format!("link-arg={}", arg));
+    }
+
+    rustc_args.extend(toolchain.rustc_args.iter().cloned());
+
+    // The cc crate prefers variables with the target as a suffix over CC etc.
+    // so that build scripts compiling for the host aren't affected.
+    let suffix = triple.replace('-', "_");
+    let mut env = Vec::new();
+
+    for (name, value) in [("CC", tools.cc), ("CXX", tools.cxx), ("AR", tools.ar)] {
+        if let Some(value) = value {
+            env.push((format!("{}_{}", name, suffix), value));
+        }
+    }
+
+    if !tools.cflags.is_empty() {
+        env.push((format!("CFLAGS_{}", suffix), tools.cflags.join(" ")));
+        env.push((format!("CXXFLAGS_{}", suffix), tools.cflags.join(" ")));
+    }
+
+    Ok(Resolved { rustc_args, env })
+}
+
+fn preset(preset: Preset, cpu: &str, wrappers: &Path) -> anyhow::Result<Tools> {
+    let tools = match preset {
+        Preset::Gnu => Tools {
+            linker: Some(format!("{}-linux-gnu-ld", cpu)),
+            cc: Some(format!("{}-linux-gnu-gcc", cpu)),
+            cxx: Some(format!("{}-linux-gnu-g++", cpu)),
+            ar: Some(format!("{}-linux-gnu-ar", cpu)),
+            ..Default::default()
+        },
+        Preset::Clang => {
+            let target = format!("--target={}-linux-musl", cpu);
+
+            Tools {
+                linker: Some(String::from("clang")),
+                cc: Some(String::from("clang")),
+                cxx: Some(String::from("clang++")),
+                ar: Some(String::from("llvm-ar")),
+                cflags: vec![target.clone()],
+                link_args: vec![target, String::from("-fuse-ld=lld")],
+            }
+        }
+        Preset::Zig => {
+            // rustc and the cc crate expect a single program, so the target goes into a script.
+            let target = format!("{}-linux-musl", cpu);
+
+            let cc = wrapper(wrappers, "zig-cc", &format!("zig cc -target {}", target))?;
+
+            Tools {
+                linker: Some(cc.clone()),
+                cc: Some(cc),
+                cxx: Some(wrapper(
+                    wrappers,
+                    "zig-c++",
+                    &format!("zig c++ -target {}", target),
+                )?),
+                ar: Some(wrapper(wrappers, "zig-ar", "zig ar")?),
+                ..Default::default()
+            }
+        }
+        Preset::MuslCross => Tools {
+            linker: Some(format!("{}-linux-musl-gcc", cpu)),
+            cc: Some(format!("{}-linux-musl-gcc", cpu)),
+            cxx: Some(format!("{}-linux-musl-g++", cpu)),
+            ar: Some(format!("{}-linux-musl-ar", cpu)),
+            ..Default::default()
+        },
+    };
+
+    Ok(tools)
+}
+
+/// Writes a shell script to `dir` that runs `command` with its arguments
+/// and returns its path. The file name contains a digest of the script,
+/// so the path stays the same between runs and cargo's fingerprints remain valid.
+fn wrapper(dir: &Path, name: &str, command: &str) -> anyhow::Result<String> {
+    let script = format!("#!/bin/sh\nexec {} \"$@\"\n", command);
+    let hash = crate::instance::sha256(script.as_bytes());
+    let path = dir.join(format!("{}-{}", name, &hash[..16]));
+
+    if !path.exists() {
+        fs::create_dir_all(dir)?;
+
+        // Written under a temporary name so that other runs never see a partial script.
+        let tmp = tempfile::NamedTempFile::new_in(dir)?;
+        fs::write(tmp.path(), script)?;
+        fs::set_permissions(tmp.path(), fs::Permissions::from_mode(0o755))?;
+        tmp.persist(&path)?;
+    }
+
+    Ok(path.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zig_wrappers_are_stable() {
+        let dir = tempfile::tempdir().unwrap();
+        let toolchain = Toolchain {
+            preset: Some(Preset::Zig),
+            ..Default::default()
+        };
+
+        let first = resolve(
+            &toolchain,
+            "aarch64",
+            "aarch64-unknown-linux-musl",
+            dir.path(),
+        )
+        .unwrap();
+        let second = resolve(
+            &toolchain,
+            "aarch64",
+            "aarch64-unknown-linux-musl",
+            dir.path(),
+        )
+        .unwrap();
+
+        assert_eq!(first.rustc_args, second.rustc_args);
+        assert_eq!(first.env, second.env);
+
+        let cc = &first
+            .env
+            .iter()
+            .find(|(key, _)| key == "CC_aarch64_unknown_linux_musl")
+            .unwrap()
+            .1;
+        assert!(Path::new(cc).starts_with(dir.path()));
+        assert_eq!(
+            fs::read_to_string(cc).unwrap(),
+            "#!/bin/sh\nexec zig cc -target aarch64-linux-musl \"$@\"\n"
+        );
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+    }
+}
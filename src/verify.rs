@@ -0,0 +1,359 @@
+use crate::elf;
+use crate::inspect;
+use crate::mbr::{self, Mbr, SECTOR_SIZE};
+use crate::progress;
+use crate::squashfs::{self, Node, NodeKind};
+use crate::{MiB, RPI_DTBS, RPI_FIRMWARE};
+
+use anyhow::{anyhow, bail};
+use std::fs::File;
+use std::io::{prelude::*, SeekFrom};
+
+/// Files every boot partition contains.
+const BOOT_FILES: [&str; 3] = ["vmlinuz", "cmdline.txt", "config.txt"];
+
+const PARTITION_SECTORS: u32 = 256 * MiB / SECTOR_SIZE as u32;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Image file or device to verify.
+    path: String,
+    /// Architecture the image is meant for. Supported: x86_64 rpi.
+    #[arg(short = 'a', long = "architecture")]
+    arch: String,
+}
+
+/// Counts failed checks so that all of them run before giving up.
+#[derive(Debug, Default)]
+struct Checks {
+    failed: usize,
+}
+
+impl Checks {
+    fn check(&mut self, name: &str, result: anyhow::Result<()>) {
+        progress::check(name, &result);
+
+        if result.is_err() {
+            self.failed += 1;
+        }
+    }
+}
+
+pub fn verify(args: Args) -> anyhow::Result<()> {
+    if elf::machine(&args.arch).is_none() {
+        bail!("invalid architecture (supported: x86_64 rpi)");
+    }
+
+    let mut file = File::open(&args.path)?;
+
+    let size = mbr::size(&mut file)?;
+    let mbr = Mbr::read(&mut file)?;
+
+    let mut checks = Checks::default();
+
+    checks.check(
+        "MBR signature",
+        if mbr.signature_valid {
+            Ok(())
+        } else {
+            Err(anyhow!("missing 0x55AA"))
+        },
+    );
+    checks.check("partition geometry", check_geometry(&mbr, size));
+
+    // Names and contents of the files in the root directory of the boot partition.
+    let mut boot_files = Vec::new();
+
+    let result = match &mbr.partitions[0] {
+        Some(partition) => partition.open(&file).and_then(|mut partition| {
+            let fs = fatfs::FileSystem::new(&mut partition, fatfs::FsOptions::new())?;
+
+            for entry in fs.root_dir().iter() {
+                let entry = entry?;
+                let mut buf = Vec::new();
+
+                if entry.is_file() && BOOT_FILES.contains(&entry.file_name().as_str()) {
+                    entry.to_file().read_to_end(&mut buf)?;
+                }
+
+                boot_files.push((entry.file_name(), buf));
+            }
+
+            Ok(())
+        }),
+        None => Err(anyhow!("no boot partition")),
+    };
+
+    checks.check("boot filesystem", result);
+
+    let find = |name: &str| {
+        boot_files
+            .iter()
+            .find(|(file, _)| file == name)
+            .map(|(_, contents)| contents.as_slice())
+    };
+
+    for name in BOOT_FILES {
+        let result = match find(name) {
+            Some(_) => Ok(()),
+            None => Err(anyhow!("missing")),
+        };
+
+        checks.check(&format!("boot file {}", name), result);
+    }
+
+    if args.arch == "rpi" {
+        let missing: Vec<_> = RPI_FIRMWARE
+            .iter()
+            .chain(RPI_DTBS.iter())
+            .filter(|name| find(name).is_none())
+            .copied()
+            .collect();
+
+        let result = if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("missing {}", missing.join(", ")))
+        };
+
+        checks.check("RPi firmware", result);
+    }
+
+    for (name, lba) in [
+        ("vmlinuz", mbr.kernel_lba),
+        ("cmdline.txt", mbr.cmdline_lba),
+    ] {
+        if let Some(contents) = find(name) {
+            checks.check(
+                &format!("bootloader LBA of {}", name),
+                check_lba(&mut file, lba, contents),
+            );
+        }
+    }
+
+    let cmdline = find("cmdline.txt").map(String::from_utf8_lossy);
+    let slot = cmdline.as_deref().and_then(inspect::active_slot);
+    let result = match slot {
+        Some(slot) => check_root(&file, &mbr, slot, &args.arch),
+        None => Err(anyhow!(
+            "can't determine the active slot from the kernel command line"
+        )),
+    };
+
+    checks.check(
+        &format!("root filesystem {}", slot.unwrap_or("(active)")),
+        result,
+    );
+
+    if checks.failed > 0 {
+        bail!("{} checks failed", checks.failed);
+    }
+
+    progress::info("Image is consistent");
+    Ok(())
+}
+
+/// Checks that the partitions are where the packer puts them.
+fn check_geometry(mbr: &Mbr, size: u64) -> anyhow::Result<()> {
+    const FAT: u8 = 0xc;
+    const LINUX: u8 = 0x83;
+
+    let expected = [("boot", FAT), ("root A", LINUX), ("root B", LINUX)];
+
+    for (i, (name, kind)) in expected.into_iter().enumerate() {
+        let partition = match mbr.partitions[i] {
+            Some(partition) => partition,
+            None => bail!("{} partition missing", name),
+        };
+
+        let start = 2048 + i as u32 * PARTITION_SECTORS;
+
+        if partition.start_lba != start || partition.sectors != PARTITION_SECTORS {
+            bail!(
+                "{} partition is at LBA {} with {} sectors, expected LBA {} with {} sectors",
+                name,
+                partition.start_lba,
+                partition.sectors,
+                start,
+                PARTITION_SECTORS
+            );
+        }
+        if partition.kind != kind {
+            bail!(
+                "{} partition has type 0x{:02x}, expected 0x{:02x}",
+                name,
+                partition.kind,
+                kind
+            );
+        }
+        if partition.active != (i == 0) {
+            bail!("only the boot partition may be active");
+        }
+    }
+
+    if let Some(data) = mbr.partitions[3] {
+        let start = 2048 + 3 * PARTITION_SECTORS;
+
+        if data.start_lba != start {
+            bail!(
+                "data partition starts at LBA {}, expected LBA {}",
+                data.start_lba,
+                start
+            );
+        }
+        if data.kind != LINUX && data.kind != FAT {
+            bail!("data partition has unknown type 0x{:02x}", data.kind);
+        }
+        if data.active {
+            bail!("only the boot partition may be active");
+        }
+    }
+
+    let end = mbr
+        .partitions
+        .iter()
+        .flatten()
+        .map(|partition| partition.start() + partition.size())
+        .max()
+        .unwrap_or(0);
+
+    if end > size {
+        bail!(
+            "partitions end at byte {} but the destination only holds {} bytes",
+            end,
+            size
+        );
+    }
+
+    Ok(())
+}
+
+/// Checks that `lba` points at `contents`, i.e. that the bootloader
+/// loads the same data the FAT filesystem contains.
+fn check_lba(file: &mut File, lba: u32, contents: &[u8]) -> anyhow::Result<()> {
+    if lba == 0 {
+        bail!("not set");
+    }
+
+    let mut buf = vec![0; contents.len()];
+
+    file.seek(SeekFrom::Start(lba as u64 * SECTOR_SIZE))?;
+    file.read_exact(&mut buf)?;
+
+    if buf != contents {
+        bail!("LBA {} doesn't point at the file contents", lba);
+    }
+
+    Ok(())
+}
+
+/// Checks that the root filesystem in `slot` contains an init for `arch`.
+fn check_root(file: &File, mbr: &Mbr, slot: &str, arch: &str) -> anyhow::Result<()> {
+    let partition = match (slot, mbr.partitions[1], mbr.partitions[2]) {
+        ("A", Some(partition), _) | ("B", _, Some(partition)) => partition,
+        _ => bail!("partition missing"),
+    };
+
+    let squashfs = squashfs::extract(&mut partition.open(file)?)?;
+    let nodes = squashfs::list(squashfs.path())?;
+
+    let path = find_init(&nodes)?;
+
+    elf::check(&squashfs::cat(squashfs.path(), &path)?, arch)
+        .map_err(|e| anyhow!("{}: {}", path, e))
+}
+
+/// Follows hardlinks and a few levels of symlinks from /bin/init to a regular file.
+fn find_init(nodes: &[Node]) -> anyhow::Result<String> {
+    let mut path = String::from("/bin/init");
+
+    for _ in 0..8 {
+        let node = match nodes.iter().find(|node| node.path == path) {
+            Some(node) => node,
+            None => bail!("{} missing", path),
+        };
+
+        match (node.kind, &node.target) {
+            (NodeKind::File, _) => return Ok(path),
+            (NodeKind::Hardlink, Some(target)) => path = target.clone(),
+            (NodeKind::Symlink, Some(target)) => path = symlink_target(&path, target),
+            _ => bail!("{} is not a regular file", path),
+        }
+    }
+
+    bail!("too many levels of symbolic links")
+}
+
+/// Returns the absolute path the symlink at `link` points to.
+/// Relative targets are resolved against the directory of the link.
+fn symlink_target(link: &str, target: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+
+    if !target.starts_with('/') {
+        components.extend(link.split('/').filter(|component| !component.is_empty()));
+        components.pop();
+    }
+
+    for component in target.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+
+    format!("/{}", components.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(path: &str, kind: NodeKind, target: Option<&str>) -> Node {
+        Node {
+            path: path.to_owned(),
+            kind,
+            mode: 0o755,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            target: target.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn symlink_targets() {
+        assert_eq!(
+            symlink_target("/bin/init", "rustkrazy_init"),
+            "/bin/rustkrazy_init"
+        );
+        assert_eq!(symlink_target("/bin/init", "../sbin/./init"), "/sbin/init");
+        assert_eq!(
+            symlink_target("/bin/init", "/usr/bin/init"),
+            "/usr/bin/init"
+        );
+        assert_eq!(symlink_target("/init", "../../bin/init"), "/bin/init");
+    }
+
+    #[test]
+    fn find_init_follows_links() {
+        let nodes = [
+            node("/bin/init", NodeKind::Symlink, Some("multicall")),
+            node(
+                "/bin/multicall",
+                NodeKind::Hardlink,
+                Some("/sbin/multicall"),
+            ),
+            node("/sbin/multicall", NodeKind::File, None),
+        ];
+        assert_eq!(find_init(&nodes).unwrap(), "/sbin/multicall");
+
+        let loop_nodes = [node("/bin/init", NodeKind::Symlink, Some("init"))];
+        assert!(find_init(&loop_nodes).is_err());
+
+        let dangling = [node("/bin/init", NodeKind::Symlink, Some("missing"))];
+        assert!(find_init(&dangling).is_err());
+    }
+}